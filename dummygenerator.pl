@@ -70,7 +70,7 @@ for (1..$num_runs) {
         my $cells = int(rand 20000);
 
 
-        print "INSERT INTO sample (run,name,dna_nr,project,lims_id,primer_set,id,cells) VALUES ";
+        print "INSERT INTO sample (run,name,dna_nr,project,lims_id,primer_set,id,cells,key) VALUES ";
         print "('$run_name', '$name', '$dna_nr', ";
         if(rand(100)>$undef_rate) {
             print "'$project'";
@@ -82,7 +82,7 @@ for (1..$num_runs) {
         print_sometimes $lims_id, 1;
         print ",'$primer_set',$id,";
         print_sometimes $cells, 1;
-        print ");\n";
+        print ",'$run_name/$id');\n";
 
         for (1..$num_fastqs) {
             my $filename = "/mnt/foo/bar/" . random_string(8) . "/"
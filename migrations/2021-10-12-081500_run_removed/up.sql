@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE run ADD COLUMN removed timestamp;
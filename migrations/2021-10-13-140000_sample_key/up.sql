@@ -0,0 +1,12 @@
+-- Your SQL goes here
+ALTER TABLE sample ADD COLUMN key character varying(300);
+
+-- same scheme as run::sample_key(): run name, slash, sanitized sample name and
+-- an occurrence counter for names that appear more than once within a run
+UPDATE sample SET key = run || '/' || regexp_replace(name, '[^A-Za-z0-9_.-]', '_', 'g');
+UPDATE sample SET key = dup.key || '#' || dup.n
+    FROM (SELECT id, key, row_number() OVER (PARTITION BY key ORDER BY id) AS n FROM sample) dup
+    WHERE sample.id = dup.id AND dup.n > 1;
+
+ALTER TABLE sample ALTER COLUMN key SET NOT NULL;
+ALTER TABLE ONLY sample ADD CONSTRAINT sample_key_unique UNIQUE (key);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE update_log (
+    id serial primary key,
+    started timestamp not null,
+    finished timestamp not null,
+    report text not null
+);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE run_issue (
+    id serial primary key,
+    run character varying(100) NOT NULL REFERENCES run(name) ON UPDATE CASCADE ON DELETE CASCADE,
+    kind character varying(50) NOT NULL,
+    message text NOT NULL
+);
+CREATE INDEX idx_run_issue_run ON run_issue USING btree (run);
+
+-- paths that could not be parsed into a run at all, until they can
+CREATE TABLE run_failure (
+    path text primary key,
+    error text NOT NULL,
+    failed timestamp NOT NULL default now()
+);
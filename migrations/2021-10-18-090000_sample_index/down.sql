@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE sample DROP COLUMN sample_number;
+ALTER TABLE sample DROP COLUMN lane;
+ALTER TABLE sample DROP COLUMN i5_index;
+ALTER TABLE sample DROP COLUMN i5_index_id;
+ALTER TABLE sample DROP COLUMN i7_index;
+ALTER TABLE sample DROP COLUMN i7_index_id;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE sample ADD COLUMN i7_index_id varchar;
+ALTER TABLE sample ADD COLUMN i7_index varchar;
+ALTER TABLE sample ADD COLUMN i5_index_id varchar;
+ALTER TABLE sample ADD COLUMN i5_index varchar;
+ALTER TABLE sample ADD COLUMN lane int;
+ALTER TABLE sample ADD COLUMN sample_number int;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE fastq DROP COLUMN sha256;
+ALTER TABLE fastq DROP COLUMN md5;
+ALTER TABLE fastq DROP COLUMN mtime;
+ALTER TABLE fastq DROP COLUMN size;
+ALTER TABLE fastq DROP COLUMN lane;
+ALTER TABLE fastq DROP COLUMN read;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE fastq ADD COLUMN read varchar;
+ALTER TABLE fastq ADD COLUMN lane int;
+ALTER TABLE fastq ADD COLUMN size bigint;
+ALTER TABLE fastq ADD COLUMN mtime timestamp;
+ALTER TABLE fastq ADD COLUMN md5 varchar;
+ALTER TABLE fastq ADD COLUMN sha256 varchar;
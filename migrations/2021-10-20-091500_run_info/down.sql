@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE run DROP COLUMN read_structure;
+ALTER TABLE run DROP COLUMN run_number;
+ALTER TABLE run DROP COLUMN flowcell;
+ALTER TABLE run DROP COLUMN instrument;
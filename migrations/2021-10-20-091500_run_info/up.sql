@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE run ADD COLUMN instrument varchar;
+ALTER TABLE run ADD COLUMN flowcell varchar;
+ALTER TABLE run ADD COLUMN run_number int;
+ALTER TABLE run ADD COLUMN read_structure varchar;
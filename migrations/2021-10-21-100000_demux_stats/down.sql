@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE run DROP COLUMN bases_q30;
+ALTER TABLE run DROP COLUMN bases;
+ALTER TABLE run DROP COLUMN reads;
+ALTER TABLE sample DROP COLUMN bases_q30;
+ALTER TABLE sample DROP COLUMN bases;
+ALTER TABLE sample DROP COLUMN reads;
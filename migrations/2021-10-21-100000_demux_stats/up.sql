@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE sample ADD COLUMN reads bigint;
+ALTER TABLE sample ADD COLUMN bases bigint;
+ALTER TABLE sample ADD COLUMN bases_q30 bigint;
+ALTER TABLE run ADD COLUMN reads bigint;
+ALTER TABLE run ADD COLUMN bases bigint;
+ALTER TABLE run ADD COLUMN bases_q30 bigint;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE fastq DROP COLUMN mean_quality;
+ALTER TABLE fastq DROP COLUMN reads;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE fastq ADD COLUMN reads bigint;
+ALTER TABLE fastq ADD COLUMN mean_quality double precision;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE samplesheet DROP COLUMN modified;
+ALTER TABLE samplesheet DROP COLUMN name;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE samplesheet ADD COLUMN name text NOT NULL DEFAULT '';
+ALTER TABLE samplesheet ADD COLUMN modified timestamp NOT NULL DEFAULT now();
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE extract_file;
+DROP TABLE extract_job;
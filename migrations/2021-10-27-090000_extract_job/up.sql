@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE extract_job (
+    id serial primary key,
+    basket_id integer not null REFERENCES samplesheet(id) ON DELETE CASCADE,
+    target text not null,
+    -- the basket's contents when the job was submitted, see BasketContent
+    content text not null,
+    status character varying(20) not null default 'queued',
+    created timestamp not null default now(),
+    started timestamp,
+    finished timestamp,
+    message text
+);
+CREATE INDEX idx_extract_job_status ON extract_job USING btree (status);
+-- a folder can only be written by one job at a time
+CREATE UNIQUE INDEX idx_extract_job_active_target ON extract_job USING btree (target) WHERE status IN ('queued', 'running');
+
+CREATE TABLE extract_file (
+    id serial primary key,
+    job_id integer not null REFERENCES extract_job(id) ON DELETE CASCADE,
+    filename text not null,
+    size bigint,
+    status character varying(20) not null default 'pending',
+    message text
+);
+CREATE INDEX idx_extract_file_job_id ON extract_file USING btree (job_id);
@@ -0,0 +1,187 @@
+//! Versioned JSON API for pipelines and scripts, mounted at `/api/v1`.
+//!
+//! Lists are paginated with the `limit` and `offset` parameters and returned as
+//! `{"total": .., "offset": .., "limit": .., "items": [..]}`. Errors are returned
+//! as `{"error": {"status": .., "message": ".."}}` with the respective HTTP status.
+
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
+use rocket::{Catcher, Request, Route};
+use serde::Serialize;
+
+use crate::filter::{Column, Expr, Order, LATEST_RUNS_FIRST};
+use crate::models::{Fastq, Run, Sample};
+use crate::vaultdb::VaultDatabase;
+
+/// Page size if the client doesn't ask for one
+const DEFAULT_LIMIT: usize = 100;
+
+/// Largest page size a client can ask for
+const MAX_LIMIT: usize = 1000;
+
+/// A page of a paginated list
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    /// Number of items on all pages
+    total: i64,
+    offset: usize,
+    limit: usize,
+    items: Vec<T>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    status: u16,
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    error: ApiError,
+}
+
+impl ApiError {
+    fn new(status: Status, message: &str) -> Self {
+        ApiError { status: status.code, message: message.to_string() }
+    }
+
+    fn bad_request(message: &str) -> Self {
+        ApiError::new(Status::BadRequest, message)
+    }
+
+    fn not_found(message: &str) -> Self {
+        ApiError::new(Status::NotFound, message)
+    }
+
+    fn internal(e: diesel::result::Error) -> Self {
+        error!("API request failed: {}", e);
+        ApiError::new(Status::InternalServerError, "Database error")
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        status::Custom(status, Json(ErrorBody { error: self })).respond_to(req)
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Checks the requested page size
+fn page_limit(limit: Option<usize>) -> Result<usize, ApiError> {
+    match limit {
+        None => Ok(DEFAULT_LIMIT),
+        Some(l) if l > 0 && l <= MAX_LIMIT => Ok(l),
+        Some(_) => Err(ApiError::bad_request(&format!("limit must be between 1 and {}", MAX_LIMIT))),
+    }
+}
+
+fn parse_filter(filter: Option<String>) -> Result<Option<Expr>, ApiError> {
+    match filter {
+        Some(f) => crate::filter::parse(&f, &mut Vec::new())
+            .map_err(|e| ApiError::bad_request(&format!("Invalid filter: {}", e))),
+        None => Ok(None),
+    }
+}
+
+/// Samples matching a filter as used in the web interface, i.e. `project=MS_ALL cells>=15000`
+#[get("/samples?<filter>&<sort>&<limit>&<offset>")]
+async fn samples(conn: VaultDatabase, filter: Option<String>, sort: Option<String>, limit: Option<usize>, offset: Option<usize>) -> ApiResult<Page<Sample>> {
+    let limit = page_limit(limit)?;
+    let offset = offset.unwrap_or(0);
+    let filter = parse_filter(filter)?;
+    let order = match sort {
+        Some(s) => Order::parse(&s).map_err(|e| ApiError::bad_request(&e))?,
+        None => Order::default(),
+    };
+    if order.column == Column::Samples {
+        return Err(ApiError::bad_request("Only runs can be sorted by samples"));
+    }
+
+    let result = conn.run(move |c| {
+        crate::vaultdb::query(c, "%.fastq.gz", filter.as_ref(), order, Some(limit), offset)
+            .map_err(|e| e.to_string())
+    }).await;
+    match result {
+        Ok(page) => Ok(Json(Page {
+            total: page.total,
+            offset,
+            limit,
+            items: page.samples.into_iter().map(|(s, _)| s).collect(),
+        })),
+        Err(e) => {
+            error!("API request failed: {}", e);
+            Err(ApiError::new(Status::InternalServerError, "Query failed"))
+        },
+    }
+}
+
+/// FASTQs of a sample
+#[get("/samples/<id>/fastqs")]
+async fn sample_fastqs(conn: VaultDatabase, id: i32) -> ApiResult<Vec<Fastq>> {
+    conn.run(move |c| crate::vaultdb::sample_fastqs(c, id)).await
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(&format!("No sample with id {}", id)))
+}
+
+/// Runs still present on disk matching a filter on run columns, i.e.
+/// `investigator=%Smith% date>=2021-01-01`, most recent first by default
+#[get("/runs?<filter>&<sort>&<limit>&<offset>")]
+async fn runs(conn: VaultDatabase, filter: Option<String>, sort: Option<String>, limit: Option<usize>, offset: Option<usize>) -> ApiResult<Page<Run>> {
+    let limit = page_limit(limit)?;
+    let offset = offset.unwrap_or(0);
+    let filter = parse_filter(filter)?;
+    let order = match sort {
+        Some(s) => Order::parse(&s).map_err(|e| ApiError::bad_request(&e))?,
+        None => LATEST_RUNS_FIRST,
+    };
+    if !order.column.is_run_column() {
+        return Err(ApiError::bad_request(&format!("Runs can not be sorted by {}", order.column.name())));
+    }
+    if let Some(Err(e)) = filter.as_ref().map(|f| f.to_run_expression()) {
+        return Err(ApiError::bad_request(&e));
+    }
+
+    let result = conn.run(move |c| {
+        crate::vaultdb::runs(c, filter.as_ref(), order, Some(limit), offset)
+            .map_err(|e| e.to_string())
+    }).await;
+    match result {
+        Ok(page) => Ok(Json(Page {
+            total: page.total,
+            offset,
+            limit,
+            items: page.runs.into_iter().map(|(r, _)| r).collect(),
+        })),
+        Err(e) => {
+            error!("API request failed: {}", e);
+            Err(ApiError::new(Status::InternalServerError, "Query failed"))
+        },
+    }
+}
+
+#[get("/runs/<name>")]
+async fn run(conn: VaultDatabase, name: String) -> ApiResult<Run> {
+    let n = name.clone();
+    conn.run(move |c| crate::vaultdb::get_run(c, &n)).await
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(&format!("No run named {}", name)))
+}
+
+/// Reports unknown routes, malformed parameters and the like as error objects
+#[catch(default)]
+fn default_catcher(status: Status, _req: &Request) -> ApiError {
+    ApiError::new(status, status.reason().unwrap_or("Unknown error"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![samples, sample_fastqs, runs, run]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
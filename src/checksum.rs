@@ -0,0 +1,73 @@
+//! Checksums of FASTQ files, computed while the data passes through anyway.
+
+use std::io::Read;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// MD5 and SHA-256 of a file as lower case hex strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksums {
+    pub md5: String,
+    pub sha256: String,
+}
+
+/// A reader that hashes everything that is read through it
+pub struct HashingReader<R> {
+    inner: R,
+    md5: Md5,
+    sha256: Sha256,
+    bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            md5: Md5::new(),
+            sha256: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn finish(self) -> Checksums {
+        Checksums {
+            md5: format!("{:x}", self.md5.finalize()),
+            sha256: format!("{:x}", self.sha256.finalize()),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.md5.update(&buf[..n]);
+        self.sha256.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads `r` to the end and returns its checksums
+pub fn checksums<R: Read>(r: R) -> std::io::Result<Checksums> {
+    let mut reader = HashingReader::new(r);
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        let c = checksums("abc".as_bytes()).unwrap();
+        assert_eq!(c.md5, "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(c.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
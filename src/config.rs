@@ -14,7 +14,7 @@ pub enum Command {
         #[structopt(short,long)]
         samplesheet: Option<PathBuf>,
 
-        /// Filter
+        /// Filter, i.e. "project=MS_ALL (primer_set=TRG OR primer_set=TRB)". Multiple filters must all match
         #[structopt(long)]
         filter: Vec<String>,
 
@@ -52,7 +52,54 @@ pub enum Command {
         /// Root folder for Cellsheet/spikeINBC lookup
         #[structopt(default_value = "/mnt/L/05-Molekulargenetik/09-NGS/01-Markerscreening", long, parse(from_os_str))]
         celldir: PathBuf,
+
+        /// Only add or update this run folder or zip file (may be given multiple times)
+        #[structopt(long, parse(from_os_str), conflicts_with = "since")]
+        run: Vec<PathBuf>,
+
+        /// Only add or update runs modified on or after this date (YYYY-MM-DD)
+        #[structopt(long)]
+        since: Option<chrono::NaiveDate>,
+
+        /// Compute MD5 and SHA-256 checksums of new or modified FASTQs
+        #[structopt(long)]
+        checksums: bool,
+
+        /// Count reads and mean quality of new or modified FASTQs
+        #[structopt(long)]
+        count_reads: bool,
+
+        /// Write an update report. Format depends on filename (.json, text otherwise)
+        #[structopt(long, parse(from_os_str))]
+        report: Option<PathBuf>,
+
+        /// Store the update report in the database
+        #[structopt(long)]
+        log_db: bool,
+    },
+
+    /// Watch the run folder and register new runs as soon as they are complete
+    ///
+    /// Run folders count as complete once the instrument has written CopyComplete.txt or
+    /// RTAComplete.txt. Folders without either are never registered by watch, use update --run for them.
+    Watch {
+        /// Root folder for sequencing runs
+        #[structopt(default_value = "/mnt/ngs/01-Rohdaten", long, parse(from_os_str))]
+        rundir: PathBuf,
+
+        /// Root folder for Cellsheet/spikeINBC lookup
+        #[structopt(default_value = "/mnt/L/05-Molekulargenetik/09-NGS/01-Markerscreening", long, parse(from_os_str))]
+        celldir: PathBuf,
+
+        /// Seconds between two scans of the run folder
+        #[structopt(default_value = "60", long)]
+        interval: u64,
+
+        /// Seconds a new run must stay unchanged before it is registered
+        #[structopt(default_value = "300", long)]
+        settle: u64,
     },
+
     /// Start the Rocket handler
     Web,
 }
@@ -0,0 +1,66 @@
+//! Statistics computed by streaming through gzipped FASTQ files, for runs that
+//! lack demultiplexing statistics.
+
+use std::io::{BufRead, BufReader, Read};
+
+use flate2::read::MultiGzDecoder;
+
+/// Read count and quality of a FASTQ file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadStats {
+    pub reads: i64,
+    /// Mean Phred quality over all bases, if there are any
+    pub mean_quality: Option<f64>,
+}
+
+/// Decompresses a gzipped FASTQ and counts its reads and quality scores.
+/// Quality scores are expected in Phred+33 encoding.
+pub fn read_stats<R: Read>(r: R) -> std::io::Result<ReadStats> {
+    let mut reader = BufReader::new(MultiGzDecoder::new(r));
+    let mut line: Vec<u8> = Vec::new();
+    let mut line_no: u64 = 0;
+    let mut reads: i64 = 0;
+    let mut bases: u64 = 0;
+    let mut quality_sum: u64 = 0;
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        // records are header, sequence, separator and quality
+        match line_no % 4 {
+            0 => reads += 1,
+            3 => {
+                for q in line.iter().filter(|c| !c.is_ascii_whitespace()) {
+                    quality_sum += u64::from(q.saturating_sub(33));
+                    bases += 1;
+                }
+            },
+            _ => {},
+        }
+        line_no += 1;
+    }
+
+    Ok(ReadStats {
+        reads,
+        mean_quality: if bases > 0 { Some(quality_sum as f64 / bases as f64) } else { None },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn count() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"@read1\nACGT\n+\nIIII\n@read2\nACGT\n+\n++++\n").unwrap();
+        let stats = read_stats(&gz.finish().unwrap()[..]).unwrap();
+        assert_eq!(stats.reads, 2);
+        assert_eq!(stats.mean_quality, Some(25.0));
+    }
+}
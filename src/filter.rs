@@ -0,0 +1,831 @@
+//! Sample filters as used by the `--filter` option and the web interface, i.e.
+//! `project=MS_ALL cells>=15000` or `(primer_set=TRG OR primer_set=TRB) date>=2021-01-01`.
+//! Besides sample columns, the attributes of the sample's run can be filtered on.
+//!
+//! Filters are parsed into a typed representation and compiled to diesel
+//! expressions, so filter values always end up as bound parameters.
+//!
+//! The syntax is:
+//! * `column op value` with `op` one of `=`, `!=`, `<`, `<=`, `>`, `>=`
+//! * `column in (a, b, c)` and `column not in (a, b, c)`
+//! * `!=`, `not in` and `NOT` also match samples where the column is empty
+//! * values containing spaces or special characters in double or single quotes
+//! * filters separated by whitespace or `AND` must all match, `OR` has lower
+//!   precedence than `AND`, `NOT` negates and parentheses group
+//! * a term without column is taken as FASTQ file name pattern
+//!
+//! Query results can be sorted by any column except `filename`, see `Order`. Runs can
+//! additionally be sorted by their number of `samples`.
+
+use std::convert::TryFrom;
+
+use chrono::NaiveDate;
+use diesel::dsl::not;
+use diesel::expression::nullable::Nullable;
+use diesel::expression::operators::Eq;
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::query_builder::BoxedSelectStatement;
+use diesel::query_source::joins::{Inner, Join, JoinOn};
+use diesel::sql_types::{BigInt, Bool};
+
+use crate::schema::{fastq, run, sample};
+
+sql_function!(fn coalesce(x: Bool, y: Bool) -> Bool);
+
+/// Samples joined with their runs, the source filters are applied to
+pub type SampleRun = JoinOn<Join<sample::table, run::table, Inner>, Eq<Nullable<sample::run>, Nullable<run::name>>>;
+
+/// A filter compiled to an SQL expression on samples and their runs
+pub type SampleExpression = Box<dyn BoxableExpression<SampleRun, Pg, SqlType = Bool>>;
+
+/// A filter compiled to an SQL expression on the `run` table
+pub type RunExpression = Box<dyn BoxableExpression<run::table, Pg, SqlType = Bool>>;
+
+/// A query for runs
+pub type RunQuery<'a> = BoxedSelectStatement<'a, run::SqlType, run::table, Pg>;
+
+/// Number of samples of a run, for queries on the `run` table. A subquery, since diesel
+/// can't mix aggregate and plain columns in a select clause.
+pub fn run_sample_count() -> SqlLiteral<BigInt> {
+    sql("(SELECT count(*) FROM sample WHERE sample.run = run.name)")
+}
+
+/// A query for samples that filters on samples and their runs
+pub type SampleQuery<'a> = BoxedSelectStatement<'a, sample::SqlType, SampleRun, Pg>;
+
+/// Columns that can be filtered on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Run,
+    Name,
+    DnaNr,
+    Project,
+    PrimerSet,
+    /// Any of the sample's FASTQ file names
+    Filename,
+    Cells,
+    LimsId,
+    I7IndexId,
+    I7Index,
+    I5IndexId,
+    I5Index,
+    Lane,
+    SampleNumber,
+    Reads,
+    /// Date of the sample's run
+    Date,
+    Investigator,
+    Assay,
+    Chemistry,
+    /// Description of the sample's run
+    Description,
+    /// Number of samples of a run, only for sorting runs
+    Samples,
+}
+
+/// All columns with their names in filter expressions
+const COLUMNS: [(Column, &str); 21] = [
+    (Column::Run, "run"),
+    (Column::Name, "name"),
+    (Column::DnaNr, "dna_nr"),
+    (Column::Project, "project"),
+    (Column::PrimerSet, "primer_set"),
+    (Column::Filename, "filename"),
+    (Column::Cells, "cells"),
+    (Column::LimsId, "lims_id"),
+    (Column::I7IndexId, "i7_index_id"),
+    (Column::I7Index, "i7_index"),
+    (Column::I5IndexId, "i5_index_id"),
+    (Column::I5Index, "i5_index"),
+    (Column::Lane, "lane"),
+    (Column::SampleNumber, "sample_number"),
+    (Column::Reads, "reads"),
+    (Column::Date, "date"),
+    (Column::Investigator, "investigator"),
+    (Column::Assay, "assay"),
+    (Column::Chemistry, "chemistry"),
+    (Column::Description, "description"),
+    (Column::Samples, "samples"),
+];
+
+impl Column {
+    pub fn from_name(name: &str) -> Option<Column> {
+        COLUMNS.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)).map(|(c, _)| *c)
+    }
+
+    pub fn name(&self) -> &'static str {
+        COLUMNS.iter().find(|(c, _)| c == self).map(|(_, n)| *n).unwrap()
+    }
+
+    /// Numeric columns are compared as numbers, all others with `ILIKE`
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Column::Cells | Column::LimsId | Column::Lane | Column::SampleNumber | Column::Reads)
+    }
+
+    /// Columns of the `run` table, which can be used to filter and sort runs, and the
+    /// sample count runs can be sorted by
+    pub fn is_run_column(&self) -> bool {
+        matches!(self, Column::Run | Column::Date | Column::Investigator | Column::Assay | Column::Chemistry | Column::Description | Column::Samples)
+    }
+
+    fn is_int4(&self) -> bool {
+        matches!(self, Column::Cells | Column::Lane | Column::SampleNumber)
+    }
+}
+
+/// Comparison operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Equality for numbers and dates, `ILIKE` pattern match for text
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Number(i64),
+    Date(NaiveDate),
+}
+
+/// A single comparison, like `cells>=15000`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub column: Column,
+    pub op: Op,
+    pub value: Value,
+}
+
+impl Filter {
+    /// Checks and converts the value for the given column
+    pub fn new(column: Column, op: Op, value: &str) -> Result<Filter, String> {
+        if column == Column::Samples {
+            return Err(String::from("samples can only be used to sort runs"));
+        }
+        let value = if column.is_numeric() {
+            let n = value
+                .parse::<i64>()
+                .map_err(|_| format!("{} needs a number, got '{}'", column.name(), value))?;
+            if column.is_int4() && i32::try_from(n).is_err() {
+                return Err(format!("{} is out of range for {}", n, column.name()));
+            }
+            Value::Number(n)
+        } else if column == Column::Date {
+            Value::Date(NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|_| format!("date needs the format YYYY-MM-DD, got '{}'", value))?)
+        } else if op != Op::Eq {
+            return Err(format!("{} only supports = and !=", column.name()));
+        } else if column == Column::DnaNr {
+            // DNA numbers are stored without prefix
+            Value::Text(value.replace("D-", ""))
+        } else {
+            Value::Text(value.to_string())
+        };
+        Ok(Filter { column, op, value })
+    }
+
+    /// Compiles the filter to an SQL expression
+    pub fn to_expression(&self) -> Result<SampleExpression, String> {
+        macro_rules! compare {
+            ($column:expr, $value:expr) => {
+                match self.op {
+                    Op::Eq => Box::new($column.eq($value)) as SampleExpression,
+                    Op::Lt => Box::new($column.lt($value)),
+                    Op::Le => Box::new($column.le($value)),
+                    Op::Gt => Box::new($column.gt($value)),
+                    Op::Ge => Box::new($column.ge($value)),
+                }
+            };
+        }
+
+        Ok(match &self.value {
+            Value::Text(v) => {
+                let v = v.clone();
+                match self.column {
+                    Column::Run => Box::new(sample::run.ilike(v)),
+                    Column::Name => Box::new(sample::name.ilike(v)),
+                    Column::DnaNr => Box::new(sample::dna_nr.ilike(v)),
+                    Column::Project => Box::new(sample::project.ilike(v)),
+                    Column::PrimerSet => Box::new(sample::primer_set.ilike(v)),
+                    Column::Filename => Box::new(sample::id.eq_any(
+                        fastq::table.select(fastq::sample_id).filter(fastq::filename.ilike(v))
+                    )),
+                    Column::I7IndexId => Box::new(sample::i7_index_id.ilike(v)),
+                    Column::I7Index => Box::new(sample::i7_index.ilike(v)),
+                    Column::I5IndexId => Box::new(sample::i5_index_id.ilike(v)),
+                    Column::I5Index => Box::new(sample::i5_index.ilike(v)),
+                    Column::Investigator => Box::new(run::investigator.ilike(v)),
+                    Column::Assay => Box::new(run::assay.ilike(v)),
+                    Column::Chemistry => Box::new(run::chemistry.ilike(v)),
+                    Column::Description => Box::new(run::description.ilike(v)),
+                    c => return Err(format!("{} is not a text column", c.name())),
+                }
+            },
+            Value::Number(v) => {
+                let v32 = || i32::try_from(*v).map_err(|_| format!("{} is out of range for {}", v, self.column.name()));
+                match self.column {
+                    Column::Cells => compare!(sample::cells, v32()?),
+                    Column::LimsId => compare!(sample::lims_id, *v),
+                    Column::Lane => compare!(sample::lane, v32()?),
+                    Column::SampleNumber => compare!(sample::sample_number, v32()?),
+                    Column::Reads => compare!(sample::reads, *v),
+                    c => return Err(format!("{} is not a numeric column", c.name())),
+                }
+            },
+            Value::Date(d) => match self.column {
+                Column::Date => compare!(run::date, *d),
+                c => return Err(format!("{} is not a date column", c.name())),
+            },
+        })
+    }
+
+    /// Compiles the filter to an SQL expression on the `run` table, which is only
+    /// possible for run columns
+    pub fn to_run_expression(&self) -> Result<RunExpression, String> {
+        Ok(match (&self.value, self.column) {
+            (Value::Text(v), Column::Run) => Box::new(run::name.ilike(v.clone())),
+            (Value::Text(v), Column::Investigator) => Box::new(run::investigator.ilike(v.clone())),
+            (Value::Text(v), Column::Assay) => Box::new(run::assay.ilike(v.clone())),
+            (Value::Text(v), Column::Chemistry) => Box::new(run::chemistry.ilike(v.clone())),
+            (Value::Text(v), Column::Description) => Box::new(run::description.ilike(v.clone())),
+            (Value::Date(d), Column::Date) => match self.op {
+                Op::Eq => Box::new(run::date.eq(*d)),
+                Op::Lt => Box::new(run::date.lt(*d)),
+                Op::Le => Box::new(run::date.le(*d)),
+                Op::Gt => Box::new(run::date.gt(*d)),
+                Op::Ge => Box::new(run::date.ge(*d)),
+            },
+            (_, c) => return Err(format!("Runs can not be filtered by {}", c.name())),
+        })
+    }
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Filter(Filter),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+/// Compiles an `Expr` by calling `$method` on its filters
+macro_rules! compile_expr {
+    ($expr:expr, $method:ident) => {
+        Ok(match $expr {
+            Expr::Filter(f) => f.$method()?,
+            // a comparison with NULL is neither true nor false, so negating it alone would
+            // never match rows without a value
+            Expr::Not(e) => Box::new(not(coalesce(e.$method()?, false))),
+            Expr::And(exprs) => {
+                let mut result = exprs.first().ok_or("Empty AND expression")?.$method()?;
+                for e in &exprs[1..] {
+                    result = Box::new(result.and(e.$method()?));
+                }
+                result
+            },
+            Expr::Or(exprs) => {
+                let mut result = exprs.first().ok_or("Empty OR expression")?.$method()?;
+                for e in &exprs[1..] {
+                    result = Box::new(result.or(e.$method()?));
+                }
+                result
+            },
+        })
+    };
+}
+
+impl Expr {
+    /// Compiles the expression to an SQL expression
+    pub fn to_expression(&self) -> Result<SampleExpression, String> {
+        compile_expr!(self, to_expression)
+    }
+
+    /// Compiles the expression to an SQL expression on runs alone. Fails if it
+    /// contains sample columns.
+    pub fn to_run_expression(&self) -> Result<RunExpression, String> {
+        compile_expr!(self, to_run_expression)
+    }
+
+    /// Combines expressions so that all of them have to match
+    pub fn all(mut exprs: Vec<Expr>) -> Option<Expr> {
+        match exprs.len() {
+            0 => None,
+            1 => exprs.pop(),
+            _ => Some(Expr::And(exprs)),
+        }
+    }
+}
+
+/// Sort order of query results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Order {
+    pub column: Column,
+    pub descending: bool,
+}
+
+/// Default order of run lists, most recent runs first
+pub const LATEST_RUNS_FIRST: Order = Order { column: Column::Date, descending: true };
+
+impl Default for Order {
+    fn default() -> Self {
+        Order { column: Column::Run, descending: false }
+    }
+}
+
+impl Order {
+    /// Parses a column name, prefixed with `-` for descending order
+    pub fn parse(order: &str) -> Result<Order, String> {
+        let order = order.trim();
+        let (name, descending) = match order.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (order, false),
+        };
+        let column = Column::from_name(name).ok_or_else(|| format!("Unknown sort column '{}'", name))?;
+        if column == Column::Filename {
+            return Err(String::from("Results can not be sorted by filename"));
+        }
+        Ok(Order { column, descending })
+    }
+
+    /// Sorts the query results. Ties are broken by sample name and id, so that
+    /// pages of results are stable.
+    pub fn apply<'a>(&self, q: SampleQuery<'a>) -> SampleQuery<'a> {
+        macro_rules! order {
+            ($column:expr) => {
+                if self.descending {
+                    q.order(($column.desc(), sample::name.asc(), sample::id.asc()))
+                } else {
+                    q.order(($column.asc(), sample::name.asc(), sample::id.asc()))
+                }
+            };
+        }
+
+        match self.column {
+            Column::Run => order!(sample::run),
+            Column::Name => order!(sample::name),
+            Column::DnaNr => order!(sample::dna_nr),
+            Column::Project => order!(sample::project),
+            Column::PrimerSet => order!(sample::primer_set),
+            Column::Cells => order!(sample::cells),
+            Column::LimsId => order!(sample::lims_id),
+            Column::I7IndexId => order!(sample::i7_index_id),
+            Column::I7Index => order!(sample::i7_index),
+            Column::I5IndexId => order!(sample::i5_index_id),
+            Column::I5Index => order!(sample::i5_index),
+            Column::Lane => order!(sample::lane),
+            Column::SampleNumber => order!(sample::sample_number),
+            Column::Reads => order!(sample::reads),
+            Column::Date => order!(run::date),
+            Column::Investigator => order!(run::investigator),
+            Column::Assay => order!(run::assay),
+            Column::Chemistry => order!(run::chemistry),
+            Column::Description => order!(run::description),
+            // rejected by `parse` or only for runs, fall back to the default order
+            Column::Filename | Column::Samples => order!(sample::run),
+        }
+    }
+
+    /// Sorts runs, which is only possible by run columns
+    pub fn apply_to_runs<'a>(&self, q: RunQuery<'a>) -> Result<RunQuery<'a>, String> {
+        macro_rules! order {
+            ($column:expr) => {
+                if self.descending {
+                    q.order(($column.desc(), run::name.asc()))
+                } else {
+                    q.order(($column.asc(), run::name.asc()))
+                }
+            };
+        }
+
+        Ok(match self.column {
+            Column::Run => order!(run::name),
+            Column::Date => order!(run::date),
+            Column::Investigator => order!(run::investigator),
+            Column::Assay => order!(run::assay),
+            Column::Chemistry => order!(run::chemistry),
+            Column::Description => order!(run::description),
+            Column::Samples => order!(run_sample_count()),
+            c => return Err(format!("Runs can not be sorted by {}", c.name())),
+        })
+    }
+}
+
+impl std::fmt::Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", if self.descending { "-" } else { "" }, self.column.name())
+    }
+}
+
+/// A syntax error in a filter expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Character position in the filter string, starting at 1
+    pub position: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Option<Op>),
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// Splits a filter string into tokens with their character positions.
+/// `!=` is represented as `Token::Op(None)`.
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let token = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            },
+            '(' => { i += 1; Token::LParen },
+            ')' => { i += 1; Token::RParen },
+            ',' => { i += 1; Token::Comma },
+            '=' => { i += 1; Token::Op(Some(Op::Eq)) },
+            '<' | '>' | '!' => {
+                let two = chars.get(i + 1) == Some(&'=');
+                i += if two { 2 } else { 1 };
+                match (c, two) {
+                    ('<', false) => Token::Op(Some(Op::Lt)),
+                    ('<', true) => Token::Op(Some(Op::Le)),
+                    ('>', false) => Token::Op(Some(Op::Gt)),
+                    ('>', true) => Token::Op(Some(Op::Ge)),
+                    ('!', true) => Token::Op(None),
+                    _ => return Err(ParseError { position: start + 1, message: String::from("Expected = after !") }),
+                }
+            },
+            '"' | '\'' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(ParseError { position: start + 1, message: String::from("Unterminated quoted value") }),
+                        Some('\\') if i + 1 < chars.len() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        },
+                        Some(q) if *q == c => {
+                            i += 1;
+                            break;
+                        },
+                        Some(other) => {
+                            value.push(*other);
+                            i += 1;
+                        },
+                    }
+                }
+                Token::Quoted(value)
+            },
+            _ => {
+                while i < chars.len() && !chars[i].is_whitespace() && !"()<>=!,".contains(chars[i]) {
+                    i += 1;
+                }
+                Token::Word(chars[start..i].iter().collect())
+            },
+        };
+        tokens.push((start + 1, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Position reported for errors at the end of the input
+    end: usize,
+    bare_terms: &'a mut Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset).map(|(_, t)| t)
+    }
+
+    fn is_keyword(&self, offset: usize, keyword: &str) -> bool {
+        matches!(self.peek_at(offset), Some(t) if t.is_keyword(keyword))
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map(|(p, _)| *p).unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.peek().cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        Err(ParseError { position: self.position(), message: message.to_string() })
+    }
+
+    fn expect(&mut self, token: Token, description: &str) -> Result<(), ParseError> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("Expected {}", description))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_and()?];
+        while self.is_keyword(0, "or") {
+            self.pos += 1;
+            terms.push(self.parse_and()?);
+        }
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { Expr::Or(terms) })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut terms = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                None | Some(Token::RParen) => break,
+                Some(t) if t.is_keyword("or") => break,
+                Some(t) if t.is_keyword("and") => {
+                    self.pos += 1;
+                    terms.push(self.parse_unary()?);
+                },
+                Some(_) => terms.push(self.parse_unary()?),
+            }
+        }
+        Ok(Expr::all(terms).unwrap())
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some(t) if t.is_keyword("not") => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            },
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let e = self.parse_or()?;
+                self.expect(Token::RParen, "closing parenthesis")?;
+                Ok(e)
+            },
+            Some(Token::Word(_)) | Some(Token::Quoted(_)) => self.parse_comparison(),
+            Some(_) => self.error("Expected a filter"),
+            None => self.error("Unexpected end of filter"),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position();
+        let (word, quoted) = match self.next() {
+            Some(Token::Word(w)) => (w, false),
+            Some(Token::Quoted(q)) => (q, true),
+            _ => unreachable!(),
+        };
+
+        let negated_in = self.is_keyword(0, "not") && self.is_keyword(1, "in");
+        let is_in = self.is_keyword(0, "in");
+        let op = match self.peek() {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        };
+        if quoted || (op.is_none() && !is_in && !negated_in) {
+            // no column given, so the term is a file name pattern
+            self.bare_terms.push(word.clone());
+            return Ok(Expr::Filter(Filter {
+                column: Column::Filename,
+                op: Op::Eq,
+                value: Value::Text(format!("%{}%", word)),
+            }));
+        }
+
+        let column = Column::from_name(&word).ok_or_else(|| ParseError {
+            position: start,
+            message: format!("Unknown filter column '{}'", word),
+        })?;
+
+        if is_in || negated_in {
+            self.pos += if negated_in { 2 } else { 1 };
+            self.expect(Token::LParen, "( after in")?;
+            let mut alternatives = vec![self.parse_value(column, Op::Eq)?];
+            while self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+                alternatives.push(self.parse_value(column, Op::Eq)?);
+            }
+            self.expect(Token::RParen, "closing parenthesis of in list")?;
+            let e = if alternatives.len() == 1 { alternatives.pop().unwrap() } else { Expr::Or(alternatives) };
+            return Ok(if negated_in { Expr::Not(Box::new(e)) } else { e });
+        }
+
+        self.pos += 1;
+        match op.unwrap() {
+            Some(op) => self.parse_value(column, op),
+            None => Ok(Expr::Not(Box::new(self.parse_value(column, Op::Eq)?))),
+        }
+    }
+
+    /// Parses the value of a comparison
+    fn parse_value(&mut self, column: Column, op: Op) -> Result<Expr, ParseError> {
+        let position = self.position();
+        match self.next() {
+            Some(Token::Word(v)) | Some(Token::Quoted(v)) => Filter::new(column, op, &v)
+                .map(Expr::Filter)
+                .map_err(|message| ParseError { position, message }),
+            _ => Err(ParseError { position, message: format!("Expected a value for {}", column.name()) }),
+        }
+    }
+}
+
+/// Parses a filter string. Terms without column are interpreted as FASTQ file
+/// name patterns and returned in `bare_terms`, so callers can point this out.
+/// Returns `None` if the filter string is empty.
+pub fn parse(input: &str, bare_terms: &mut Vec<String>) -> Result<Option<Expr>, ParseError> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end: input.chars().count() + 1,
+        bare_terms,
+    };
+    let e = parser.parse_or()?;
+    if parser.peek().is_some() {
+        return parser.error("Unexpected closing parenthesis");
+    }
+    Ok(Some(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(column: Column, op: Op, value: Value) -> Expr {
+        Expr::Filter(Filter { column, op, value })
+    }
+
+    fn text(column: Column, value: &str) -> Expr {
+        filter(column, Op::Eq, Value::Text(String::from(value)))
+    }
+
+    fn parse_ok(input: &str) -> Expr {
+        parse(input, &mut Vec::new()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(parse_ok("cells>=15000"), filter(Column::Cells, Op::Ge, Value::Number(15000)));
+        assert_eq!(parse_ok("reads < 100"), filter(Column::Reads, Op::Lt, Value::Number(100)));
+        assert_eq!(parse_ok("project=MS_'ALL"), text(Column::Project, "MS_'ALL"));
+        assert_eq!(parse_ok("project=\"World Dominance\""), text(Column::Project, "World Dominance"));
+        assert_eq!(parse_ok("dna_nr=D-21-%"), text(Column::DnaNr, "21-%"));
+        assert_eq!(parse_ok("Investigator='Jane Doe'"), text(Column::Investigator, "Jane Doe"));
+        assert_eq!(parse_ok("primer_set!=TRG"), Expr::Not(Box::new(text(Column::PrimerSet, "TRG"))));
+        assert_eq!(
+            parse_ok("date>=2021-01-01"),
+            filter(Column::Date, Op::Ge, Value::Date(NaiveDate::from_ymd_opt(2021, 1, 1).unwrap()))
+        );
+    }
+
+    #[test]
+    fn combinations() {
+        assert_eq!(
+            parse_ok("run=21% primer_set=TRG OR primer_set=TRB"),
+            Expr::Or(vec![
+                Expr::And(vec![text(Column::Run, "21%"), text(Column::PrimerSet, "TRG")]),
+                text(Column::PrimerSet, "TRB"),
+            ])
+        );
+        assert_eq!(
+            parse_ok("run=21% AND (primer_set=TRG or primer_set=TRB)"),
+            Expr::And(vec![
+                text(Column::Run, "21%"),
+                Expr::Or(vec![text(Column::PrimerSet, "TRG"), text(Column::PrimerSet, "TRB")]),
+            ])
+        );
+        assert_eq!(
+            parse_ok("lane not in (1, 2)"),
+            Expr::Not(Box::new(Expr::Or(vec![
+                filter(Column::Lane, Op::Eq, Value::Number(1)),
+                filter(Column::Lane, Op::Eq, Value::Number(2)),
+            ])))
+        );
+
+        let mut bare = Vec::new();
+        assert_eq!(parse("MS_ALL", &mut bare), Ok(Some(text(Column::Filename, "%MS_ALL%"))));
+        assert_eq!(bare, vec![String::from("MS_ALL")]);
+        assert_eq!(parse("  ", &mut bare), Ok(None));
+    }
+
+    #[test]
+    fn negation_matches_null() {
+        let conn = match crate::vaultdb::tests::test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        let name = "211020_M70821_0003_000000000-NULLS";
+        conn.test_transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_into(run::table)
+                .values((
+                    run::name.eq(name),
+                    run::date.eq(NaiveDate::from_ymd_opt(2021, 10, 20).unwrap()),
+                    run::assay.eq(""),
+                    run::chemistry.eq(""),
+                    run::investigator.eq(""),
+                    run::path.eq("/nonexistent"),
+                ))
+                .execute(&conn)?;
+            for (sample, primer_set) in &[("S1", None), ("S2", Some("TRG"))] {
+                diesel::insert_into(sample::table)
+                    .values((
+                        sample::run.eq(name),
+                        sample::name.eq(sample),
+                        sample::project.eq("P1"),
+                        sample::key.eq(format!("{}-{}", name, sample)),
+                        sample::primer_set.eq(primer_set),
+                    ))
+                    .execute(&conn)?;
+            }
+
+            let matching = |input: &str| -> Vec<String> {
+                sample::table
+                    .inner_join(run::table)
+                    .select(sample::name)
+                    .filter(sample::run.eq(name))
+                    .filter(parse_ok(input).to_expression().unwrap())
+                    .load(&conn)
+                    .unwrap()
+            };
+            assert_eq!(matching("primer_set!=TRG"), vec![String::from("S1")]);
+            assert_eq!(matching("primer_set not in (TRG, TRB)"), vec![String::from("S1")]);
+            assert_eq!(matching("NOT primer_set=TRG"), vec![String::from("S1")]);
+            assert_eq!(matching("primer_set=TRG"), vec![String::from("S2")]);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn order() {
+        assert_eq!(Order::parse("-reads"), Ok(Order { column: Column::Reads, descending: true }));
+        assert_eq!(Order::parse("date"), Ok(Order { column: Column::Date, descending: false }));
+        assert!(Order::parse("filename").is_err());
+        assert_eq!(Order::parse("-cells").unwrap().to_string(), "-cells");
+        assert!(!Order::parse("-cells").unwrap().column.is_run_column());
+        assert_eq!(Order::parse("-samples"), Ok(Order { column: Column::Samples, descending: true }));
+        assert!(Order::parse("-samples").unwrap().column.is_run_column());
+    }
+
+    #[test]
+    fn run_filters() {
+        assert!(parse_ok("investigator=Smith OR date>2021-01-01").to_run_expression().is_ok());
+        assert!(parse_ok("run=21% cells>1").to_run_expression().is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |input: &str| parse(input, &mut Vec::new()).unwrap_err();
+        assert_eq!(error("color=red").position, 1);
+        assert_eq!(error("cells=many").position, 7);
+        assert_eq!(error("lane=3000000000").position, 6);
+        assert_eq!(error("project<MS").position, 9);
+        assert_eq!(error("date>2021-13-01").position, 6);
+        assert_eq!(error("(run=21%").position, 9);
+        assert_eq!(error("run=21%)").position, 8);
+        assert_eq!(error("name=\"abc").position, 6);
+        assert_eq!(error("lane in (1,").position, 12);
+        assert_eq!(error("cells>=").message, "Expected a value for cells");
+    }
+}
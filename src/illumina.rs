@@ -0,0 +1,373 @@
+//! Parser for Illumina sample sheets (SampleSheet.csv) as written by the Illumina
+//! Experiment Manager or the instrument control software, and for the run
+//! metadata files RunInfo.xml and RunParameters.xml.
+//!
+//! A sample sheet is a CSV file divided into sections like `[Header]`, `[Reads]`,
+//! `[Settings]` and `[Data]`. Some sections contain key/value pairs, others are
+//! tables whose first line names the columns.
+//!
+//! Newer instruments (NextSeq 1000/2000, BCL Convert) write version 2 sheets with
+//! `FileFormatVersion,2` in the header. Their sample table is `[BCLConvert_Data]`
+//! instead of `[Data]`, and projects are listed separately in `[Cloud_Data]`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+
+use chrono::NaiveDate;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Splits CSV content into records of fields.
+///
+/// Fields may be quoted with `"`, in which case they may contain separators, line
+/// breaks and quotes (written as `""`).
+pub(crate) fn parse_csv(input: &str) -> Vec<Vec<String>> {
+    let mut records: Vec<Vec<String>> = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); },
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' => {},
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            },
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+/// A table section like `[Data]`
+#[derive(Debug, Default)]
+pub struct Table {
+    /// Column names as given in the first line of the section
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Index of a column. Column names are matched case-insensitively.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the value of a given column in a row. Empty values are treated as missing.
+    pub fn get<'a>(&self, row: &'a [String], column: &str) -> Option<&'a str> {
+        self.column(column)
+            .and_then(|idx| row.get(idx))
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// A parsed Illumina sample sheet
+#[derive(Debug, Default)]
+pub struct IlluminaSampleSheet {
+    /// Records of each section, indexed by the section name without brackets
+    sections: HashMap<String, Vec<Vec<String>>>,
+}
+
+impl IlluminaSampleSheet {
+    pub fn from_reader<R: Read>(mut r: R) -> Result<Self> {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        // sample sheets edited on lab computers are frequently not UTF-8
+        Ok(Self::parse(&String::from_utf8_lossy(&buf)))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let content = content.trim_start_matches('\u{feff}');
+        let mut sheet = IlluminaSampleSheet::default();
+        let mut current: Option<String> = None;
+
+        for mut record in parse_csv(content) {
+            // spreadsheet programs pad all lines to the same number of columns
+            while record.last().map(|f| f.trim().is_empty()).unwrap_or(false) {
+                record.pop();
+            }
+            if record.is_empty() {
+                continue;
+            }
+
+            let first = record[0].trim();
+            if first.starts_with('[') && first.ends_with(']') {
+                let name = first[1..first.len() - 1].to_string();
+                sheet.sections.entry(name.clone()).or_default();
+                current = Some(name);
+            } else if let Some(section) = &current {
+                sheet.sections.get_mut(section).unwrap().push(record);
+            }
+        }
+
+        sheet
+    }
+
+    pub fn has_section(&self, name: &str) -> bool {
+        self.sections.contains_key(name)
+    }
+
+    /// Sample sheet format version. Sheets without `FileFormatVersion` are version 1,
+    /// unless they have a `[BCLConvert_Data]` section.
+    pub fn version(&self) -> u32 {
+        match self.settings("Header").get("FileFormatVersion").and_then(|v| v.parse().ok()) {
+            Some(v) => v,
+            None if self.has_section("BCLConvert_Data") => 2,
+            None => 1,
+        }
+    }
+
+    /// Name of the section that lists the samples
+    pub fn data_section(&self) -> &'static str {
+        if self.version() >= 2 {
+            "BCLConvert_Data"
+        } else {
+            "Data"
+        }
+    }
+
+    /// Interprets a section as key/value pairs, like `[Header]` or `[Settings]`
+    pub fn settings(&self, section: &str) -> HashMap<String, String> {
+        self.sections
+            .get(section)
+            .map(|records| records
+                .iter()
+                .map(|r| (r[0].trim().to_string(), r.get(1).map(|v| v.trim().to_string()).unwrap_or_default()))
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// Interprets a section as table, like `[Data]`
+    pub fn table(&self, section: &str) -> Option<Table> {
+        let mut records = self.sections.get(section)?.iter();
+        let columns = records.next()?.iter().map(|c| c.trim().to_string()).collect();
+        Some(Table {
+            columns,
+            rows: records.cloned().collect(),
+        })
+    }
+}
+
+/// A read of a sequencing run
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadInfo {
+    pub cycles: u32,
+    pub index: bool,
+}
+
+/// Run metadata from RunInfo.xml and RunParameters.xml
+#[derive(Debug, Default, PartialEq)]
+pub struct RunInfo {
+    pub instrument: Option<String>,
+    pub flowcell: Option<String>,
+    pub run_number: Option<i32>,
+    /// Run start date
+    pub date: Option<NaiveDate>,
+    pub reads: Vec<ReadInfo>,
+}
+
+/// Text content of the first element with the given tag name
+fn xml_text<'a>(doc: &'a roxmltree::Document, tag: &str) -> Option<&'a str> {
+    doc.descendants()
+        .find(|n| n.has_tag_name(tag))
+        .and_then(|n| n.text())
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+}
+
+/// Parses the date formats found in RunInfo.xml and RunParameters.xml of different
+/// instruments and software versions, i.e. `210802`, `20210802`, `8/2/2021 9:41:47 AM`
+/// and `2021-08-02T09:41:47Z`
+fn parse_xml_date(s: &str) -> Option<NaiveDate> {
+    let date = s.split(&[' ', 'T'][..]).next()?;
+    ["%y%m%d", "%Y%m%d", "%m/%d/%Y", "%Y-%m-%d"]
+        .iter()
+        .find_map(|fmt| NaiveDate::parse_from_str(date, fmt).ok())
+}
+
+impl RunInfo {
+    /// Reads RunInfo.xml
+    pub fn parse_runinfo(&mut self, xml: &str) -> Result<()> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let run = doc.descendants().find(|n| n.has_tag_name("Run"));
+
+        self.instrument = xml_text(&doc, "Instrument").map(String::from);
+        self.flowcell = xml_text(&doc, "Flowcell").map(String::from);
+        self.run_number = run.and_then(|r| r.attribute("Number")).and_then(|n| n.parse().ok());
+        self.date = xml_text(&doc, "Date").and_then(parse_xml_date);
+        self.reads = doc.descendants()
+            .filter(|n| n.has_tag_name("Read"))
+            .filter_map(|n| Some(ReadInfo {
+                cycles: n.attribute("NumCycles")?.parse().ok()?,
+                index: n.attribute("IsIndexedRead") == Some("Y"),
+            }))
+            .collect();
+        Ok(())
+    }
+
+    /// Reads RunParameters.xml, filling in what RunInfo.xml did not tell
+    pub fn parse_runparameters(&mut self, xml: &str) -> Result<()> {
+        let doc = roxmltree::Document::parse(xml)?;
+
+        if self.instrument.is_none() {
+            self.instrument = ["ScannerID", "InstrumentID", "InstrumentSerialNumber"]
+                .iter()
+                .find_map(|t| xml_text(&doc, t))
+                .map(String::from);
+        }
+        if self.flowcell.is_none() {
+            self.flowcell = doc.descendants()
+                .find(|n| n.has_tag_name("FlowcellRFIDTag") || n.has_tag_name("FlowCellRfidTag"))
+                .and_then(|n| n.descendants().find(|c| c.has_tag_name("SerialNumber")))
+                .and_then(|n| n.text())
+                .or_else(|| xml_text(&doc, "FlowCellSerialNumber"))
+                .map(|t| t.trim().to_string());
+        }
+        if self.run_number.is_none() {
+            self.run_number = xml_text(&doc, "RunNumber").and_then(|n| n.parse().ok());
+        }
+        if self.date.is_none() {
+            self.date = xml_text(&doc, "RunStartDate").and_then(parse_xml_date);
+        }
+        Ok(())
+    }
+
+    /// Read structure in the notation of BCL Convert's OverrideCycles, i.e. `Y151;I8;I8;Y151`
+    pub fn read_structure(&self) -> Option<String> {
+        if self.reads.is_empty() {
+            return None;
+        }
+        Some(self.reads
+            .iter()
+            .map(|r| format!("{}{}", if r.index { "I" } else { "Y" }, r.cycles))
+            .collect::<Vec<String>>()
+            .join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}[Header],,,\r
+IEMFileVersion,4,,\r
+Investigator Name,JK,,\r
+Description,\"Runs, with commas\",,\r
+,,,\r
+[Reads],,,\r
+151,,,\r
+151,,,\r
+[Data],,,\r
+Sample_ID,Sample_Name,Sample_Project,Description\r
+D-21-01234_IGH,,MS_ALL,123456\r
+\"Doe, \"\"Jane\"\"\",,World Dominance,\r
+";
+
+    #[test]
+    fn sections() {
+        let s = IlluminaSampleSheet::parse(SHEET);
+        assert_eq!(s.settings("Header").get("Investigator Name").map(|s| s.as_str()), Some("JK"));
+        assert_eq!(s.settings("Header").get("Description").map(|s| s.as_str()), Some("Runs, with commas"));
+        assert_eq!(s.settings("Reads").len(), 1);
+    }
+
+    #[test]
+    fn data() {
+        let s = IlluminaSampleSheet::parse(SHEET);
+        let data = s.table("Data").unwrap();
+        assert_eq!(data.rows.len(), 2);
+        assert_eq!(data.get(&data.rows[0], "sample_id"), Some("D-21-01234_IGH"));
+        assert_eq!(data.get(&data.rows[0], "Sample_Name"), None);
+        assert_eq!(data.get(&data.rows[1], "Sample_ID"), Some("Doe, \"Jane\""));
+        assert_eq!(data.get(&data.rows[1], "Sample_Project"), Some("World Dominance"));
+        assert_eq!(data.get(&data.rows[1], "index"), None);
+    }
+
+    const SHEET_V2: &str = "[Header]
+FileFormatVersion,2
+RunName,NS2000_Run42
+InstrumentPlatform,NextSeq1k2k
+
+[Reads]
+Read1Cycles,151
+Read2Cycles,151
+Index1Cycles,10
+Index2Cycles,10
+
+[BCLConvert_Settings]
+SoftwareVersion,3.7.4
+
+[BCLConvert_Data]
+Lane,Sample_ID,Index,Index2
+1,D-21-01234_IGH,ACGTACGTAC,TTGGCCAATT
+2,D-21-01234_IGH,ACGTACGTAC,TTGGCCAATT
+
+[Cloud_Data]
+Sample_ID,ProjectName,LibraryName
+D-21-01234_IGH,MS_ALL,D-21-01234_IGH_ACGTACGTAC_TTGGCCAATT
+";
+
+    #[test]
+    fn version() {
+        assert_eq!(IlluminaSampleSheet::parse(SHEET).version(), 1);
+        assert_eq!(IlluminaSampleSheet::parse(SHEET).data_section(), "Data");
+
+        let s = IlluminaSampleSheet::parse(SHEET_V2);
+        assert_eq!(s.version(), 2);
+        assert_eq!(s.data_section(), "BCLConvert_Data");
+        let data = s.table(s.data_section()).unwrap();
+        assert_eq!(data.rows.len(), 2);
+        assert_eq!(data.get(&data.rows[1], "index2"), Some("TTGGCCAATT"));
+        let cloud = s.table("Cloud_Data").unwrap();
+        assert_eq!(cloud.get(&cloud.rows[0], "ProjectName"), Some("MS_ALL"));
+    }
+
+    #[test]
+    fn runinfo() {
+        let mut info = RunInfo::default();
+        info.parse_runinfo(r#"<?xml version="1.0"?>
+<RunInfo xmlns:xsd="http://www.w3.org/2001/XMLSchema" Version="2">
+  <Run Id="210802_M70821_0114_000000000-DCWMD" Number="114">
+    <Flowcell>000000000-DCWMD</Flowcell>
+    <Instrument>M70821</Instrument>
+    <Date>210802</Date>
+    <Reads>
+      <Read Number="1" NumCycles="151" IsIndexedRead="N" />
+      <Read Number="2" NumCycles="8" IsIndexedRead="Y" />
+      <Read Number="3" NumCycles="8" IsIndexedRead="Y" />
+      <Read Number="4" NumCycles="151" IsIndexedRead="N" />
+    </Reads>
+  </Run>
+</RunInfo>"#).unwrap();
+        info.parse_runparameters(r#"<?xml version="1.0"?>
+<RunParameters><RunNumber>1</RunNumber><RunStartDate>210801</RunStartDate><ScannerID>M00001</ScannerID></RunParameters>"#).unwrap();
+
+        assert_eq!(info.instrument.as_deref(), Some("M70821"));
+        assert_eq!(info.flowcell.as_deref(), Some("000000000-DCWMD"));
+        assert_eq!(info.run_number, Some(114));
+        assert_eq!(info.date, NaiveDate::from_ymd_opt(2021, 8, 2));
+        assert_eq!(info.read_structure().as_deref(), Some("Y151;I8;I8;Y151"));
+
+        assert_eq!(parse_xml_date("8/2/2021 9:41:47 AM"), NaiveDate::from_ymd_opt(2021, 8, 2));
+        assert_eq!(parse_xml_date("2021-08-02T09:41:47Z"), NaiveDate::from_ymd_opt(2021, 8, 2));
+    }
+}
@@ -0,0 +1,369 @@
+//! Extraction jobs, which copy the FASTQs of a basket into a directory in the background.
+//!
+//! Copying hundreds of FASTQs to a project share takes far longer than a web request
+//! may. The web interface therefore only queues a job in the database, and a worker
+//! thread of the web server carries out one job after the other, recording the
+//! status of every single file on the way. Since everything lives in the database,
+//! jobs that were queued or interrupted when the server stopped are picked up again
+//! once it is back.
+
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+use diesel::PgConnection;
+use serde::Serialize;
+
+use crate::models::{BasketContent, ExtractFile, ExtractJob, NewExtractFile, NewExtractJob};
+use crate::samplesheet::SampleSheet;
+use crate::vaultdb;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// How often the worker looks for new jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Lifecycle of an extraction job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// All files have been copied
+    Done,
+    /// The job could not be carried out or some files could not be copied
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Lifecycle of a single file of an extraction job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+impl FileStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileStatus::Pending => "pending",
+            FileStatus::Done => "done",
+            FileStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Number of files of a job by status
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Progress {
+    pub total: i64,
+    pub done: i64,
+    pub failed: i64,
+}
+
+/// Resolves the directory an extraction job writes to: a new or empty folder named
+/// `folder` directly within one of the `allowed` directories.
+pub fn target_dir(allowed: &[PathBuf], dir: &Path, folder: &str) -> std::result::Result<PathBuf, String> {
+    if !allowed.iter().any(|a| a == dir) {
+        return Err(format!("{} is not a permitted target directory", dir.display()));
+    }
+    let mut components = Path::new(folder).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => {},
+        _ => return Err(format!("Invalid folder name '{}', it must not be empty or contain path separators", folder)),
+    }
+
+    let target = dir.join(folder);
+    match target.symlink_metadata() {
+        Err(_) => Ok(target),
+        Ok(m) if m.is_dir() => match target.read_dir() {
+            Ok(mut entries) => match entries.next() {
+                None => Ok(target),
+                Some(_) => Err(format!("{} already exists and is not empty", target.display())),
+            },
+            Err(e) => Err(format!("Cannot read {}: {}", target.display(), e)),
+        },
+        Ok(_) => Err(format!("{} already exists and is not a directory", target.display())),
+    }
+}
+
+/// Queues an extraction of the contents of a basket into `target` and returns the id of
+/// the job, or `None` if another job that has not finished yet writes to `target`.
+/// The contents are stored with the job, so later changes to the basket don't affect it.
+pub fn submit(conn: &PgConnection, basket_id: i32, content: &BasketContent, target: &Path) -> QueryResult<Option<i32>> {
+    use crate::schema::extract_job;
+    let job = NewExtractJob {
+        basket_id,
+        target: target.display().to_string(),
+        content: serde_json::to_string(content).map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?,
+    };
+    // in a transaction of its own, so that a violated constraint leaves an outer one usable
+    let inserted = conn.transaction(|| diesel::insert_into(extract_job::table)
+        .values(&job)
+        .returning(extract_job::id)
+        .get_result(conn));
+    match inserted {
+        Ok(id) => Ok(Some(id)),
+        Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The most recent jobs with their progress, newest first
+pub fn jobs(conn: &PgConnection, limit: i64) -> QueryResult<Vec<(ExtractJob, Progress)>> {
+    use crate::schema::{extract_file, extract_job};
+
+    let jobs: Vec<ExtractJob> = extract_job::table
+        .order(extract_job::id.desc())
+        .limit(limit)
+        .load(conn)?;
+    let ids: Vec<i32> = jobs.iter().map(|j| j.id).collect();
+    let counts: Vec<(i32, String, i64)> = extract_file::table
+        .filter(extract_file::job_id.eq_any(ids))
+        .group_by((extract_file::job_id, extract_file::status))
+        // count(*) as raw SQL, diesel has no typed count next to grouped columns
+        .select((extract_file::job_id, extract_file::status, diesel::dsl::sql::<diesel::sql_types::BigInt>("count(*)")))
+        .load(conn)?;
+
+    Ok(jobs
+        .into_iter()
+        .map(|job| {
+            let mut progress = Progress::default();
+            for (_, status, count) in counts.iter().filter(|(id, _, _)| *id == job.id) {
+                progress.total += count;
+                if status == FileStatus::Done.as_str() {
+                    progress.done += count;
+                } else if status == FileStatus::Failed.as_str() {
+                    progress.failed += count;
+                }
+            }
+            (job, progress)
+        })
+        .collect())
+}
+
+/// A job with its files, if known
+pub fn job(conn: &PgConnection, id: i32) -> QueryResult<Option<(ExtractJob, Vec<ExtractFile>)>> {
+    use crate::schema::{extract_file, extract_job};
+
+    let job: Option<ExtractJob> = extract_job::table.find(id).first(conn).optional()?;
+    match job {
+        Some(job) => {
+            let files = extract_file::table
+                .filter(extract_file::job_id.eq(id))
+                .order(extract_file::filename)
+                .load(conn)?;
+            Ok(Some((job, files)))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Puts jobs back into the queue that were running when the server stopped
+fn requeue_interrupted(conn: &PgConnection) -> QueryResult<usize> {
+    use crate::schema::extract_job;
+    diesel::update(extract_job::table.filter(extract_job::status.eq(JobStatus::Running.as_str())))
+        .set(extract_job::status.eq(JobStatus::Queued.as_str()))
+        .execute(conn)
+}
+
+/// Marks the oldest queued job as running and returns it
+fn next_job(conn: &PgConnection) -> QueryResult<Option<ExtractJob>> {
+    use crate::schema::extract_job;
+    conn.transaction(|| {
+        let job: Option<ExtractJob> = extract_job::table
+            .filter(extract_job::status.eq(JobStatus::Queued.as_str()))
+            .order(extract_job::id)
+            .for_update()
+            .first(conn)
+            .optional()?;
+        match job {
+            Some(job) => diesel::update(extract_job::table.find(job.id))
+                .set((
+                    extract_job::status.eq(JobStatus::Running.as_str()),
+                    extract_job::started.eq(diesel::dsl::now),
+                    extract_job::finished.eq(None::<chrono::NaiveDateTime>),
+                    extract_job::message.eq(None::<String>),
+                ))
+                .get_result(conn)
+                .map(Some),
+            None => Ok(None),
+        }
+    })
+}
+
+fn finish_job(conn: &PgConnection, id: i32, status: JobStatus, message: Option<String>) -> QueryResult<usize> {
+    use crate::schema::extract_job;
+    diesel::update(extract_job::table.find(id))
+        .set((
+            extract_job::status.eq(status.as_str()),
+            extract_job::finished.eq(diesel::dsl::now),
+            extract_job::message.eq(message),
+        ))
+        .execute(conn)
+}
+
+/// The connection is shared with the threads copying files, and a panic in one of them
+/// must not take the worker down with it
+fn lock(conn: &Mutex<PgConnection>) -> MutexGuard<'_, PgConnection> {
+    conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Copies the FASTQs of the samples a job was submitted with and their sample sheet into
+/// the target directory
+fn run_job(conn: &Mutex<PgConnection>, job: &ExtractJob) -> Result<()> {
+    use crate::schema::extract_file;
+
+    let (samplesheet, sources, overrides) = {
+        let c = lock(conn);
+        let content = job.content();
+        let samples = vaultdb::samples_by_key(&c, &content.samples)?;
+        let samplesheet = SampleSheet::with_extra_cols(samples, &content.extra_cols);
+        let sources = samplesheet.fastq_sources(&c)?;
+
+        // start over with all files if the job has been interrupted before
+        let files: Vec<NewExtractFile> = samplesheet
+            .targets(&sources)
+            .into_iter()
+            .map(|(filename, f)| NewExtractFile { job_id: job.id, filename, size: f.size, status: FileStatus::Pending.as_str().to_string() })
+            .collect();
+        c.transaction(|| {
+            diesel::delete(extract_file::table.filter(extract_file::job_id.eq(job.id))).execute(&*c)?;
+            diesel::insert_into(extract_file::table).values(&files).execute(&*c)
+        })?;
+        (samplesheet, sources, content.overrides)
+    };
+
+    let target = Path::new(&job.target);
+    std::fs::create_dir_all(target)?;
+    samplesheet.write_xlsx(&overrides, &target.join("samplesheet.xlsx"))?;
+
+    samplesheet.extract_fastqs_with_progress(&sources, target, |filename, error| {
+        let status = if error.is_some() { FileStatus::Failed } else { FileStatus::Done };
+        let c = lock(conn);
+        let updated = diesel::update(extract_file::table
+                .filter(extract_file::job_id.eq(job.id))
+                .filter(extract_file::filename.eq(filename)))
+            .set((extract_file::status.eq(status.as_str()), extract_file::message.eq(error)))
+            .execute(&*c);
+        if let Err(e) = updated {
+            error!("Cannot update status of {} in job {}: {}", filename, job.id, e);
+        }
+    })
+}
+
+/// Connects to the database, retrying until it succeeds, and puts jobs back into the
+/// queue that have been interrupted while there was no connection
+fn connect(connstr: &str) -> PgConnection {
+    loop {
+        match PgConnection::establish(connstr) {
+            Ok(c) => {
+                match requeue_interrupted(&c) {
+                    Ok(0) => {},
+                    Ok(n) => info!("Resuming {} interrupted extraction jobs", n),
+                    Err(e) => error!("Cannot resume interrupted extraction jobs: {}", e),
+                }
+                return c;
+            },
+            Err(e) => {
+                error!("Extraction worker cannot connect to the database: {}", e);
+                std::thread::sleep(POLL_INTERVAL * 12);
+            },
+        }
+    }
+}
+
+/// Carries out queued jobs one after the other. Runs forever, so start it in a thread of its own.
+pub fn worker(connstr: &str) {
+    let conn = Mutex::new(connect(connstr));
+
+    loop {
+        let next = next_job(&lock(&conn));
+        match next {
+            Ok(Some(job)) => {
+                info!("Extracting basket {} to {} (job {})", job.basket_id, job.target, job.id);
+                let (status, message) = match run_job(&conn, &job) {
+                    Ok(()) => (JobStatus::Done, None),
+                    Err(e) => {
+                        warn!("Extraction job {} failed: {}", job.id, e);
+                        (JobStatus::Failed, Some(e.to_string()))
+                    },
+                };
+                let finished = finish_job(&lock(&conn), job.id, status, message);
+                if let Err(e) = finished {
+                    // the job is resumed once the connection is back
+                    error!("Cannot record the end of extraction job {}: {}", job.id, e);
+                    std::thread::sleep(POLL_INTERVAL * 12);
+                    *lock(&conn) = connect(connstr);
+                }
+            },
+            Ok(None) => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                error!("Cannot fetch extraction jobs: {}", e);
+                std::thread::sleep(POLL_INTERVAL * 12);
+                *lock(&conn) = connect(connstr);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_dirs() {
+        let base = std::env::temp_dir();
+        let allowed = vec![base.clone()];
+        let folder = format!("vault-job-test-{}", std::process::id());
+
+        assert_eq!(target_dir(&allowed, &base, &folder), Ok(base.join(&folder)));
+        assert!(target_dir(&allowed, Path::new("/etc"), &folder).is_err());
+        assert!(target_dir(&allowed, &base, "").is_err());
+        assert!(target_dir(&allowed, &base, "..").is_err());
+        assert!(target_dir(&allowed, &base, "a/b").is_err());
+        assert!(target_dir(&allowed, &base, "/etc").is_err());
+
+        // existing folders may only be used if they are empty
+        let existing = base.join(&folder);
+        std::fs::create_dir(&existing).unwrap();
+        assert!(target_dir(&allowed, &base, &folder).is_ok());
+        std::fs::write(existing.join("x"), "x").unwrap();
+        assert!(target_dir(&allowed, &base, &folder).is_err());
+        std::fs::remove_dir_all(&existing).unwrap();
+    }
+
+    #[test]
+    fn one_job_per_target() {
+        let conn = match crate::vaultdb::tests::test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        conn.test_transaction::<_, Box<dyn Error>, _>(|| {
+            let content = BasketContent::default();
+            let basket = vaultdb::create_basket(&conn, "test", &content)?;
+            let target = Path::new("/tmp/vault-job-test");
+            let first = submit(&conn, basket, &content, target)?.unwrap();
+            assert_eq!(submit(&conn, basket, &content, target)?, None);
+            assert!(submit(&conn, basket, &content, Path::new("/tmp/vault-job-test-2"))?.is_some());
+
+            // finished jobs do not block their folder anymore
+            finish_job(&conn, first, JobStatus::Failed, None)?;
+            assert!(submit(&conn, basket, &content, target)?.is_some());
+            Ok(())
+        });
+    }
+}
@@ -1,16 +1,27 @@
 #[macro_use]
 extern crate diesel;
 
+mod api;
 mod config;
+mod illumina;
 mod run;
 mod web;
 mod vaultdb;
 mod samplesheet;
+mod report;
+mod watch;
+mod checksum;
+mod stats;
+mod fastq;
+mod filter;
+mod jobs;
+mod zipstream;
 
 mod schema;
 mod models;
 
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{collections::HashMap, error::Error, io::BufRead};
 use diesel::PgConnection;
 use env_logger::Env;
@@ -45,20 +56,21 @@ fn query(conn: PgConnection, query: String, filter: Vec<String>, limit: Option<u
     }
 
     // Collect filters
-    let mut filters = HashMap::new();
-    for f in filter.into_iter() {
-        let parts = f.split('=').map(|p| p.to_string()).collect::<Vec<_>>();
-        if parts.len() == 2 {
-            filters.insert(parts[0].to_string(), parts[1].to_string());
-        } else {
-            error!("Ignoring malformed filter: {}", &f);
+    let mut filters = Vec::new();
+    for f in filter.iter() {
+        let mut bare_terms = Vec::new();
+        let parsed = filter::parse(f, &mut bare_terms).map_err(|e| format!("Invalid filter {}: {}", f, e))?;
+        for t in bare_terms {
+            warn!("Filter term {} has no column and is used as filename=%{}%", t, t);
         }
+        filters.extend(parsed);
     }
+    let filter = filter::Expr::all(filters);
 
     // run the queries one after another and append the results to candidate list
     let mut candidates: HashMap<models::Sample, Vec<String>> = HashMap::new();
     for q in queries {
-        candidates.extend(vaultdb::query(&conn, &q, &filters, limit));
+        candidates.extend(vaultdb::query(&conn, &q, filter.as_ref(), filter::Order::default(), limit, 0)?.samples);
     }
     info!("{} candidates returned.", candidates.len());
     
@@ -111,9 +123,22 @@ fn import(conn: PgConnection, extract: Option<PathBuf>, samplesheet: Option<Path
     Ok(())
 }
 
-fn update(conn: PgConnection, rundir: PathBuf, celldir: PathBuf) -> Result<()> {
-    vaultdb::flush(&conn);
-    vaultdb::update(&conn, &rundir, &celldir)
+#[allow(clippy::too_many_arguments)]
+fn update(conn: PgConnection, rundir: PathBuf, celldir: PathBuf, runs: Vec<PathBuf>, since: Option<chrono::NaiveDate>, checksums: bool, count_reads: bool, report: Option<PathBuf>, log_db: bool) -> Result<()> {
+    let update_report = if runs.is_empty() {
+        vaultdb::update(&conn, &rundir, &celldir, since, checksums, count_reads)?
+    } else {
+        vaultdb::update_runs(&conn, &runs, &celldir, checksums, count_reads)?
+    };
+
+    if let Some(report) = report {
+        info!("Writing update report to {}...", report.display());
+        update_report.write(&report)?;
+    }
+    if log_db {
+        vaultdb::store_report(&conn, &update_report)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -145,10 +170,14 @@ fn main() -> Result<()> {
             import(db, extract, samplesheet, overrides, xlsx)
         }
 
-        config::Command::Update { rundir, celldir } => {
-            update(db, rundir, celldir)
+        config::Command::Update { rundir, celldir, run, since, checksums, count_reads, report, log_db } => {
+            update(db, rundir, celldir, run, since, checksums, count_reads, report, log_db)
         }
         
+        config::Command::Watch { rundir, celldir, interval, settle } => {
+            watch::watch(&db, &rundir, &celldir, Duration::from_secs(interval), Duration::from_secs(settle))
+        }
+
         config::Command::Web => {
             let _rocket = web::rocket();
             Ok(())
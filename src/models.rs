@@ -1,10 +1,12 @@
 use crate::schema::*;
 
-use serde::Serialize;
-use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::HashMap;
 
-#[derive(Queryable,QueryableByName,Insertable,Debug,Serialize)]
+#[derive(Queryable,QueryableByName,Insertable,AsChangeset,Debug,Serialize,PartialEq)]
 #[table_name="run"]
+#[changeset_options(treat_none_as_null="true")]
 pub struct Run {
     pub name: String,
     pub date: NaiveDate,
@@ -13,6 +15,20 @@ pub struct Run {
     pub description: Option<String>,
     pub investigator: String,
     pub path: String,
+    pub removed: Option<NaiveDateTime>,
+    pub instrument: Option<String>,
+    pub flowcell: Option<String>,
+    pub run_number: Option<i32>,
+    /// Cycles per read, i.e. `Y151;I8;I8;Y151`
+    pub read_structure: Option<String>,
+    /// Reads passing filter, including undetermined reads
+    pub reads: Option<i64>,
+    /// Yield in bases
+    pub bases: Option<i64>,
+    /// Bases with quality 30 or higher
+    pub bases_q30: Option<i64>,
+    /// Cell sheet the cell counts of the samples were taken from
+    pub cellsheet: Option<String>,
 }
 
 #[derive(Queryable,QueryableByName,Debug,Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Default)]
@@ -26,10 +42,27 @@ pub struct Sample {
     pub primer_set: Option<String>,
     pub id: i32,
     pub cells: Option<i32>,
+    /// Stable identifier that survives database updates, see `run::sample_key`
+    pub key: String,
+    pub i7_index_id: Option<String>,
+    pub i7_index: Option<String>,
+    pub i5_index_id: Option<String>,
+    pub i5_index: Option<String>,
+    /// Lane the sample was sequenced on, if it was only one
+    pub lane: Option<i32>,
+    /// The `S<n>` number in FASTQ file names
+    pub sample_number: Option<i32>,
+    /// Reads passing filter according to the demultiplexing statistics
+    pub reads: Option<i64>,
+    /// Yield in bases
+    pub bases: Option<i64>,
+    /// Bases with quality 30 or higher
+    pub bases_q30: Option<i64>,
 }
 
-#[derive(Insertable,Debug,Serialize,Clone,Default)]
+#[derive(Insertable,AsChangeset,Debug,Serialize,Clone,Default,PartialEq)]
 #[table_name="sample"]
+#[changeset_options(treat_none_as_null="true")]
 pub struct NewSample {
     pub run: String,
     pub name: String,
@@ -38,13 +71,169 @@ pub struct NewSample {
     pub lims_id: Option<i64>,
     pub primer_set: Option<String>,
     pub cells: Option<i32>,
+    pub key: String,
+    pub i7_index_id: Option<String>,
+    pub i7_index: Option<String>,
+    pub i5_index_id: Option<String>,
+    pub i5_index: Option<String>,
+    pub lane: Option<i32>,
+    pub sample_number: Option<i32>,
+    pub reads: Option<i64>,
+    pub bases: Option<i64>,
+    pub bases_q30: Option<i64>,
 }
 
-#[derive(Queryable, QueryableByName, Insertable,Debug,Serialize)]
+#[derive(Queryable, QueryableByName, Insertable,Debug,Serialize,Clone,PartialEq)]
 #[table_name="fastq"]
 pub struct Fastq {
     pub filename: String,
-    pub sample_id: i32
+    pub sample_id: i32,
+    /// R1, R2, I1 or I2
+    pub read: Option<String>,
+    pub lane: Option<i32>,
+    /// File size in bytes
+    pub size: Option<i64>,
+    pub mtime: Option<NaiveDateTime>,
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub reads: Option<i64>,
+    /// Mean Phred quality over all bases
+    pub mean_quality: Option<f64>,
+}
+
+#[derive(Queryable,Debug,Serialize)]
+pub struct RunIssue {
+    pub id: i32,
+    pub run: String,
+    pub kind: String,
+    pub message: String,
+}
+
+#[derive(Insertable,Debug)]
+#[table_name="run_issue"]
+pub struct NewRunIssue {
+    pub run: String,
+    pub kind: String,
+    pub message: String,
+}
+
+/// A run path that could not be parsed into a run. It stays until the path can be
+/// ingested or disappears.
+#[derive(Queryable,Debug,Serialize)]
+pub struct RunFailure {
+    pub path: String,
+    pub error: String,
+    /// When ingesting the path failed the last time
+    pub failed: NaiveDateTime,
+}
+
+/// A named collection of samples stored on the server, so that it can be shared and resumed
+#[derive(Queryable,Debug,Serialize)]
+pub struct Basket {
+    pub id: i32,
+    pub created: NaiveDateTime,
+    /// Contents in JSON format, see `BasketContent`
+    pub basket: String,
+    pub name: String,
+    pub modified: NaiveDateTime,
+}
+
+#[derive(Insertable,Debug)]
+#[table_name="samplesheet"]
+pub struct NewBasket {
+    pub basket: String,
+    pub name: String,
+}
+
+/// What is stored in a basket
+#[derive(Serialize,Deserialize,Debug,Default,Clone,PartialEq)]
+pub struct BasketContent {
+    /// Sample keys, see `Sample::key`
+    #[serde(default)]
+    pub samples: Vec<String>,
+
+    /// Columns imported from a sample sheet, by sample key
+    #[serde(default)]
+    pub extra_cols: HashMap<String, HashMap<String, String>>,
+
+    /// Sample sheet columns to take from the imported columns instead of the database
+    #[serde(default)]
+    pub overrides: Vec<String>,
+}
+
+impl Basket {
+    /// Parses the contents of the basket. Baskets that cannot be parsed are treated as empty.
+    pub fn content(&self) -> BasketContent {
+        serde_json::from_str(&self.basket).unwrap_or_else(|e| {
+            warn!("Cannot parse basket {}: {}", self.id, e);
+            BasketContent::default()
+        })
+    }
+}
+
+/// An extraction of the FASTQs of a basket into a directory, carried out in the background
+#[derive(Queryable,Debug,Serialize)]
+pub struct ExtractJob {
+    pub id: i32,
+    pub basket_id: i32,
+    pub target: String,
+    /// Basket contents at submission in JSON format, see `BasketContent`
+    pub content: String,
+    /// See `jobs::JobStatus`
+    pub status: String,
+    pub created: NaiveDateTime,
+    pub started: Option<NaiveDateTime>,
+    pub finished: Option<NaiveDateTime>,
+    pub message: Option<String>,
+}
+
+#[derive(Insertable,Debug)]
+#[table_name="extract_job"]
+pub struct NewExtractJob {
+    pub basket_id: i32,
+    pub target: String,
+    pub content: String,
+}
+
+impl ExtractJob {
+    /// Parses the basket contents the job was submitted with. Contents that cannot be parsed are treated as empty.
+    pub fn content(&self) -> BasketContent {
+        serde_json::from_str(&self.content).unwrap_or_else(|e| {
+            warn!("Cannot parse contents of job {}: {}", self.id, e);
+            BasketContent::default()
+        })
+    }
+}
+
+/// A FASTQ to be copied by an extraction job
+#[derive(Queryable,Debug,Serialize)]
+pub struct ExtractFile {
+    pub id: i32,
+    pub job_id: i32,
+    /// Name in the target directory
+    pub filename: String,
+    pub size: Option<i64>,
+    /// See `jobs::FileStatus`
+    pub status: String,
+    pub message: Option<String>,
+}
+
+#[derive(Insertable,Debug)]
+#[table_name="extract_file"]
+pub struct NewExtractFile {
+    pub job_id: i32,
+    pub filename: String,
+    pub size: Option<i64>,
+    pub status: String,
+}
+
+#[derive(Insertable,Debug)]
+#[table_name="update_log"]
+pub struct NewUpdateLog {
+    pub started: NaiveDateTime,
+    pub finished: NaiveDateTime,
+    /// The `report::UpdateReport` in JSON format
+    pub report: String,
 }
 
 impl NewSample {
@@ -56,7 +245,17 @@ impl NewSample {
             project: s.project.clone(),
             lims_id: s.lims_id,
             primer_set: s.primer_set.clone(),
-            cells: s.cells
+            cells: s.cells,
+            key: s.key.clone(),
+            i7_index_id: s.i7_index_id.clone(),
+            i7_index: s.i7_index.clone(),
+            i5_index_id: s.i5_index_id.clone(),
+            i5_index: s.i5_index.clone(),
+            lane: s.lane,
+            sample_number: s.sample_number,
+            reads: s.reads,
+            bases: s.bases,
+            bases_q30: s.bases_q30,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,158 @@
+//! Reports on database updates, listing for every discovered run path whether it
+//! could be ingested and what went wrong if not.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::run::{CellsheetStatus, Issue, Run};
+use crate::vaultdb::UpsertStatus;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// What happened to a discovered run path during an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    New,
+    Changed,
+    Unchanged,
+    /// The path could not be parsed into a run
+    Failed,
+}
+
+impl From<UpsertStatus> for RunStatus {
+    fn from(s: UpsertStatus) -> Self {
+        match s {
+            UpsertStatus::New => RunStatus::New,
+            UpsertStatus::Changed => RunStatus::Changed,
+            UpsertStatus::Unchanged => RunStatus::Unchanged,
+        }
+    }
+}
+
+/// Ingestion diagnostics for a single run path
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub path: String,
+    /// Run name, if the path could be parsed
+    pub run: Option<String>,
+    pub status: RunStatus,
+    /// Why the run could not be ingested
+    pub error: Option<String>,
+    pub samples: usize,
+    /// FASTQs that did not match the sample sheet, see `Run::unmatched_fastqs`
+    pub unmatched_fastqs: Vec<String>,
+    /// Names of samples from the sample sheet that did not get any FASTQs
+    pub samples_without_fastqs: Vec<String>,
+    pub cellsheet: Option<CellsheetStatus>,
+    pub issues: Vec<Issue>,
+}
+
+impl RunReport {
+    /// Collects the diagnostics of a parsed run. The status is set to `Failed` until
+    /// the run has been stored.
+    pub fn from_run(path: &str, r: &Run) -> Self {
+        RunReport {
+            path: path.to_string(),
+            run: Some(r.name.clone()),
+            status: RunStatus::Failed,
+            error: None,
+            samples: r.samples.len(),
+            unmatched_fastqs: r.unmatched_fastqs.clone(),
+            samples_without_fastqs: r.samples
+                .iter()
+                .filter(|(_, files)| files.is_empty())
+                .map(|(s, _)| s.name.clone())
+                .collect(),
+            cellsheet: Some(r.cellsheet.clone()),
+            issues: r.issues.clone(),
+        }
+    }
+
+    pub fn failed(path: &str, error: &str) -> Self {
+        RunReport {
+            path: path.to_string(),
+            run: None,
+            status: RunStatus::Failed,
+            error: Some(error.to_string()),
+            samples: 0,
+            unmatched_fastqs: Vec::new(),
+            samples_without_fastqs: Vec::new(),
+            cellsheet: None,
+            issues: Vec::new(),
+        }
+    }
+}
+
+/// Report of a complete update
+#[derive(Debug, Serialize)]
+pub struct UpdateReport {
+    pub started: NaiveDateTime,
+    pub finished: NaiveDateTime,
+    pub runs: Vec<RunReport>,
+    /// Number of runs that have been marked as removed
+    pub removed: usize,
+}
+
+impl UpdateReport {
+    pub fn count(&self, status: RunStatus) -> usize {
+        self.runs.iter().filter(|r| r.status == status).count()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Update started {}, finished {}\n", self.started, self.finished);
+        text += &format!("{} paths: {} new, {} changed, {} unchanged, {} failed. {} runs removed.\n",
+            self.runs.len(),
+            self.count(RunStatus::New),
+            self.count(RunStatus::Changed),
+            self.count(RunStatus::Unchanged),
+            self.count(RunStatus::Failed),
+            self.removed);
+
+        for r in &self.runs {
+            let status = format!("{:?}", r.status).to_uppercase();
+            text += &match &r.run {
+                Some(name) => format!("\n{} {} ({}), {} samples\n", status, name, r.path, r.samples),
+                None => format!("\n{} {}\n", status, r.path),
+            };
+            if let Some(e) = &r.error {
+                text += &format!("  error: {}\n", e);
+            }
+            text += &match &r.cellsheet {
+                Some(CellsheetStatus::NotFound) => String::from("  cell sheet: not found\n"),
+                Some(CellsheetStatus::Imported { path, samples }) => format!("  cell sheet: {} samples from {}\n", samples, path),
+                Some(CellsheetStatus::Failed { path, error }) => format!("  cell sheet: {} could not be parsed: {}\n", path, error),
+                None => String::new(),
+            };
+            for i in &r.issues {
+                text += &format!("  issue: {}\n", i.message);
+            }
+            for f in &r.unmatched_fastqs {
+                text += &format!("  FASTQ not in sample sheet: {}\n", f);
+            }
+            for s in &r.samples_without_fastqs {
+                text += &format!("  sample without FASTQs: {}\n", s);
+            }
+        }
+        text
+    }
+
+    /// Writes the report to a file. Format depends on the file name (.json, text otherwise)
+    pub fn write(&self, outfile: &Path) -> Result<()> {
+        let content = match outfile.extension().and_then(|e| e.to_str()) {
+            Some("json") => self.to_json()?,
+            _ => self.to_text(),
+        };
+        File::create(outfile)?.write_all(content.as_bytes())?;
+        Ok(())
+    }
+}
@@ -1,19 +1,25 @@
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufRead;
 use std::path::{Path, PathBuf};
-use chrono::Datelike;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, Timelike};
+use rayon::prelude::*;
 use zip::ZipArchive;
 
+use crate::checksum::checksums;
+use crate::fastq::{read_stats, ReadStats};
+use crate::illumina::{IlluminaSampleSheet, RunInfo};
+use crate::stats::{Counts, DemuxStats, STATS_FILES};
 use crate::models;
 use crate::models::NewSample;
 use crate::samplesheet::normalize_dna_nr;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::io::BufReader;
 
+use serde::Serialize;
 use walkdir::WalkDir;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
@@ -28,6 +34,87 @@ pub struct Run {
     pub assay: String,
     pub description: String,
     pub chemistry: String,
+    /// Metadata from RunInfo.xml and RunParameters.xml
+    pub info: RunInfo,
+    /// Read counts of the whole run from the demultiplexing statistics
+    pub stats: Option<Counts>,
+    /// FASTQs that did not match any sample from the sample sheet. They end up in samples
+    /// recovered from their file names, unless there is no sample sheet at all.
+    pub unmatched_fastqs: Vec<String>,
+    /// Size, modification time and checksums of all FASTQs, by file name
+    pub fastq_meta: HashMap<String, FastqMeta>,
+    /// Outcome of the cell sheet lookup
+    pub cellsheet: CellsheetStatus,
+    /// Data quality problems found while parsing the run
+    pub issues: Vec<Issue>,
+}
+
+/// What is known about a FASTQ file apart from its name
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastqMeta {
+    /// File size in bytes (uncompressed size for zip entries)
+    pub size: i64,
+    pub mtime: Option<NaiveDateTime>,
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub reads: Option<i64>,
+    pub mean_quality: Option<f64>,
+}
+
+/// Kinds of data quality problems in a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    MissingSamplesheet,
+    /// A line in the [Data] section of the sample sheet could not be parsed
+    MalformedSamplesheet,
+    /// The sample sheet did not yield any samples
+    NoSamples,
+    /// Samples that could only be derived from unmatched FASTQs, without a usable name
+    UnknownSample,
+    MalformedCellsheet,
+    /// A cell sheet entry matches no sample of the run
+    CellsheetNoMatch,
+    /// A cell sheet entry matches more than one sample of the run
+    CellsheetAmbiguous,
+    /// A FASTQ file could not be read to compute its checksums or count its reads
+    UnreadableFastq,
+    /// Demultiplexing statistics were found but could not be parsed
+    MalformedStats,
+}
+
+impl IssueKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueKind::MissingSamplesheet => "missing_samplesheet",
+            IssueKind::MalformedSamplesheet => "malformed_samplesheet",
+            IssueKind::NoSamples => "no_samples",
+            IssueKind::UnknownSample => "unknown_sample",
+            IssueKind::MalformedCellsheet => "malformed_cellsheet",
+            IssueKind::CellsheetNoMatch => "cellsheet_no_match",
+            IssueKind::CellsheetAmbiguous => "cellsheet_ambiguous",
+            IssueKind::UnreadableFastq => "unreadable_fastq",
+            IssueKind::MalformedStats => "malformed_stats",
+        }
+    }
+}
+
+/// A data quality problem found while parsing a run, usually something the lab
+/// can fix in the sample sheet or cell sheet
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+/// Outcome of looking up and parsing the spikeINBC cell sheet of a run
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellsheetStatus {
+    NotFound,
+    /// The cell sheet was parsed and this many samples got a cell count
+    Imported { path: String, samples: usize },
+    Failed { path: String, error: String },
 }
 
 
@@ -43,6 +130,44 @@ fn parse_date(source: &str) -> Result<chrono::NaiveDate> {
 }
 
 
+/// Reads RunInfo.xml and RunParameters.xml. `read` returns the content of a file
+/// in the run root, if it exists.
+fn load_runinfo(run_name: &str, mut read: impl FnMut(&str) -> Option<String>) -> RunInfo {
+    let mut info = RunInfo::default();
+    if let Some(xml) = read("RunInfo.xml") {
+        if let Err(e) = info.parse_runinfo(&xml) {
+            warn!("{}: Cannot parse RunInfo.xml: {}", run_name, e);
+        }
+    }
+    // HiSeq and older MiSeq software versions write runParameters.xml
+    if let Some(xml) = read("RunParameters.xml").or_else(|| read("runParameters.xml")) {
+        if let Err(e) = info.parse_runparameters(&xml) {
+            warn!("{}: Cannot parse RunParameters.xml: {}", run_name, e);
+        }
+    }
+    info
+}
+
+/// Derives a stable sample key from the run name and the sample name (usually the
+/// sample sheet's Sample_ID).
+///
+/// Characters that are not safe to use in cookies and HTML attributes are replaced
+/// by `_`. If the key is already taken by one of `samples`, the occurrence number
+/// is appended, i.e. the second sample with the same name gets a `#2` suffix.
+pub fn sample_key(samples: &[(NewSample, Vec<String>)], run_name: &str, name: &str) -> String {
+    lazy_static! {
+        static ref RE_UNSAFE: Regex = Regex::new(r"[^A-Za-z0-9_.-]").unwrap();
+    }
+    let key = format!("{}/{}", run_name, RE_UNSAFE.replace_all(name, "_"));
+    let mut occurrence = 1;
+    let mut candidate = key.clone();
+    while samples.iter().any(|(s, _)| s.key == candidate) {
+        occurrence += 1;
+        candidate = format!("{}#{}", key, occurrence);
+    }
+    candidate
+}
+
 fn is_fastq(s: &str) -> bool {
     s.ends_with(".fastq.gz")
         && !s.contains("Data")
@@ -50,6 +175,68 @@ fn is_fastq(s: &str) -> bool {
         && !s.contains("Archiv_")
 }
 
+/// Sample number, lane and read as encoded in Illumina FASTQ file names, i.e.
+/// `Name_S1_L001_R1_001.fastq.gz`. The lane is missing if lanes have been merged.
+#[derive(Debug, PartialEq)]
+struct FastqName {
+    sample_number: i32,
+    lane: Option<i32>,
+    /// R1, R2, I1 or I2
+    read: String,
+}
+
+fn parse_fastq_name(fastq: &str) -> Option<FastqName> {
+    lazy_static! {
+        static ref RE_ILLUMINA: Regex = Regex::new(r"_S(?P<snum>\d+)(?:_L(?P<lane>\d{3}))?_(?P<read>[RI]\d)_\d{3}\.fastq\.gz$").unwrap();
+    }
+    let captures = RE_ILLUMINA.captures(fastq)?;
+    Some(FastqName {
+        sample_number: captures.name("snum")?.as_str().parse().ok()?,
+        lane: captures.name("lane").and_then(|l| l.as_str().parse().ok()),
+        read: captures.name("read")?.as_str().to_string(),
+    })
+}
+
+/// Builds the database record of a FASTQ. Read and lane are taken from the file name.
+pub fn new_fastq(filename: String, sample_id: i32, meta: Option<&FastqMeta>) -> models::Fastq {
+    let name = parse_fastq_name(&filename);
+    models::Fastq {
+        read: name.as_ref().map(|n| n.read.clone()),
+        lane: name.and_then(|n| n.lane),
+        size: meta.map(|m| m.size),
+        mtime: meta.and_then(|m| m.mtime),
+        md5: meta.and_then(|m| m.md5.clone()),
+        sha256: meta.and_then(|m| m.sha256.clone()),
+        reads: meta.and_then(|m| m.reads),
+        mean_quality: meta.and_then(|m| m.mean_quality),
+        filename,
+        sample_id,
+    }
+}
+
+/// Converts a file's modification time. Postgres timestamps only store microseconds, so
+/// anything finer is dropped or the time would never match the one from the database.
+fn file_mtime(t: std::time::SystemTime) -> NaiveDateTime {
+    let t = DateTime::<Local>::from(t).naive_local();
+    t.with_nanosecond(t.nanosecond() / 1000 * 1000).unwrap_or(t)
+}
+
+/// Converts a zip entry's modification time
+fn zip_mtime(t: zip::DateTime) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(t.year().into(), t.month().into(), t.day().into())?
+        .and_hms_opt(t.hour().into(), t.minute().into(), t.second().into())
+}
+
+/// Returns the value if all values are the same
+fn single<T: PartialEq>(mut values: impl Iterator<Item = Option<T>>) -> Option<T> {
+    let first = values.next()??;
+    if values.all(|v| v.as_ref() == Some(&first)) {
+        Some(first)
+    } else {
+        None
+    }
+}
+
 fn parse_from_fastq(samples: &mut Vec<(NewSample, Vec<String>)>, fastq: &str, run_name: &str) {
     lazy_static! {
         static ref RE_NAME: Regex = Regex::new(r"(?P<name>.*?)_S\d+_.*\.fastq\.gz$").unwrap();
@@ -94,7 +281,8 @@ fn parse_from_fastq(samples: &mut Vec<(NewSample, Vec<String>)>, fastq: &str, ru
     }
 
     if !found {
-        samples.push((s, vec![fastq.to_string()]));
+        let key = sample_key(samples, run_name, &s.name);
+        samples.push((NewSample { key, ..s }, vec![fastq.to_string()]));
     }
 }
 
@@ -134,7 +322,12 @@ fn match_fastq(sample: &NewSample, fastq: &str) -> bool {
 /// sort sample names by length (longest first), so we get the best matches
 /// before one of the shorter prefixes could match, and then remove the matched
 /// fastqs from the fastq file list
-fn assign_fastqs(mut samples: &mut Vec<(NewSample, Vec<String>)>, mut fastqs: Vec<String>, run_name: &str) -> usize {
+///
+/// Returns the FASTQs that did not match any of the given samples. These are
+/// not lost, but assigned to samples recovered from their file names.
+fn assign_fastqs(mut samples: &mut Vec<(NewSample, Vec<String>)>, mut fastqs: Vec<String>, run_name: &str) -> Vec<String> {
+    // file system order is arbitrary, but sample keys of recovered samples depend on it
+    fastqs.sort_unstable();
     samples.sort_unstable_by_key(|(s,_)| s.name.len());
     samples.reverse();
 
@@ -161,15 +354,26 @@ fn assign_fastqs(mut samples: &mut Vec<(NewSample, Vec<String>)>, mut fastqs: Ve
 
     // Create new samples, if necessary, based on what we can parse from the remaining
     // FASTQ filenames
+    fastqs.retain(|f| !f.is_empty());
     if !fastqs.is_empty() {
-        debug!("Recovering {} samples from unmatched FASTQs", fastqs.len());
+        debug!("Recovering samples from {} unmatched FASTQs", fastqs.len());
     }
     fastqs
-        .into_iter()
-        .filter(|f| !f.is_empty())
-        .for_each(|f| parse_from_fastq(&mut samples, &f, run_name));
+        .iter()
+        .for_each(|f| parse_from_fastq(&mut samples, f, run_name));
 
-    0
+    // fill in what the sample sheet did not tell
+    for (s, files) in samples.iter_mut() {
+        let names: Vec<Option<FastqName>> = files.iter().map(|f| parse_fastq_name(f)).collect();
+        if s.sample_number.is_none() {
+            s.sample_number = single(names.iter().map(|n| n.as_ref().map(|n| n.sample_number)));
+        }
+        if s.lane.is_none() {
+            s.lane = single(names.iter().map(|n| n.as_ref().and_then(|n| n.lane)));
+        }
+    }
+
+    fastqs
 }
 
 impl Run {
@@ -285,6 +489,9 @@ impl Run {
             let mut candidates: Vec<&mut models::NewSample> = self.samples.iter_mut().filter(|(s,_)| s.name == parts[0]).map(|(a,_)| a).collect();
             if candidates.len() != 1 {
                 debug!("{} cell sheet {} entry {} matches {} known samples", self.name, csheet.display(), parts[0], candidates.len());
+                let kind = if candidates.is_empty() { IssueKind::CellsheetNoMatch } else { IssueKind::CellsheetAmbiguous };
+                let message = format!("Cell sheet entry {} matches {} samples", parts[0], candidates.len());
+                self.issues.push(Issue { kind, message });
             } else {
                 candidates[0].cells = parts[1]
                     .parse::<f32>()
@@ -298,112 +505,160 @@ impl Run {
 
     /// Parses the run's SampleSheet.csv for auxiliary run information
     fn parse_samplesheet<R: Read>(&mut self, r: R, fastqs: Vec<String>, run_name: &str) -> Result<()> {
-        let b = BufReader::new(r);
-        let mut data_mode = false;
+        let sheet = IlluminaSampleSheet::from_reader(r)?;
 
-        for linebuf in b.lines().flatten() {
-
-            let mut parts: Vec<&str> = linebuf.split(',').collect();
+        let header = sheet.settings("Header");
+        let header_value = |keys: &[&str]| keys
+            .iter()
+            .find_map(|k| header.get(*k))
+            .cloned()
+            .unwrap_or_default();
+        self.investigator = header_value(&["Investigator Name"]);
+        self.assay = header_value(&["Assay"]);
+        self.description = header_value(&["Description", "RunDescription"]);
+        self.chemistry = header_value(&["Chemistry"]);
+
+        let section = sheet.data_section();
+        let data = sheet.table(section).unwrap_or_default();
+        if data.columns.is_empty() {
+            self.issues.push(Issue { kind: IssueKind::MalformedSamplesheet, message: format!("No [{}] section found", section) });
+        } else if data.column("Sample_ID").is_none() && data.column("Sample_Name").is_none() {
+            error!("{}: No Sample_ID column in [{}] section: {:?}", self.name, section, data.columns);
+            self.issues.push(Issue {
+                kind: IssueKind::MalformedSamplesheet,
+                message: format!("No Sample_ID or Sample_Name column in [{}] section, got {}", section, data.columns.join(",")),
+            });
+        }
 
-            // part of samplesheet in windows INI style
-            if !data_mode {
-                if parts.len() >= 2 {
-                    match parts[0] {
-                        "Investigator Name" => {
-                            self.investigator = parts[1].to_owned().to_string()
-                        }
-                        "Assay" => self.assay = parts[1].to_owned().to_string(),
-                        "Description" => self.description = parts[1].to_owned().to_string(),
-                        "Chemistry" => self.chemistry = parts[1].to_owned().to_string(),
-                        _ => {}
-                    }
+        // v2 sheets list projects separately
+        let mut projects: HashMap<String, String> = HashMap::new();
+        if let Some(cloud) = sheet.table("Cloud_Data") {
+            for row in cloud.rows.iter() {
+                if let (Some(id), Some(project)) = (cloud.get(row, "Sample_ID"), cloud.get(row, "ProjectName")) {
+                    projects.insert(id.to_string(), project.to_string());
                 }
-                // advance to second part, the CSV-like actual sample sheet
-                if parts[0] == "[Data]" {
-                    if parts.len() > 10 {
-                        warn!(
-                            "{}: More than 10 colums, will ignore everything after column 10",
-                            self.name
-                        );
+            }
+        }
+
+        for row in data.rows.iter() {
+            if row.len() > data.columns.len() && row[data.columns.len()..].iter().any(|f| !f.trim().is_empty()) {
+                self.issues.push(Issue {
+                    kind: IssueKind::MalformedSamplesheet,
+                    message: format!("Row has more values than the [{}] section has columns: {}", section, row.join(",")),
+                });
+            }
+
+            // the sample name is usually the Sample_ID, Sample_Name is optional
+            let name = match data.get(row, "Sample_ID").or_else(|| data.get(row, "Sample_Name")) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let lane = data.get(row, "Lane").and_then(|l| l.parse::<i32>().ok());
+
+            // samples that are sequenced on several lanes are listed once per lane
+            if data.column("Lane").is_some() {
+                if let Some((s, _)) = self.samples.iter_mut().find(|(s, _)| s.name == name) {
+                    if s.lane != lane {
+                        s.lane = None;
                     }
-                    data_mode = true;
-                }
-            // CSV-style part
-            } else {
-                if parts[0].to_ascii_lowercase() == "sample_id" {
                     continue;
                 }
-                if parts.len() < 2 {
-                    continue;
-                }
-                let mut s: models::NewSample = Default::default();
-                if parts.len() > 10 {
-                    parts.resize(10, "");
-                }
-                match parts.len() {
-                    10 => {
-                        s.project = (!parts[8].is_empty()).then(|| parts[8].to_string());
-                        s.name = parts[0].to_string();
-
-                        // if it parses as unsigned number and it's positive, it might be a
-                        // LIMS id.
-                        if let Ok(id) = parts[9].parse::<i64>() {
-                            if id > 0 {
-                                s.lims_id = Some(id);
-                            } else {
-                                s.lims_id = None;
-                            }
-                        }
-                    }
-                    9 => {
-                        s.project = (!parts[8].is_empty()).then(|| parts[8].to_string());
-                        s.name = parts[0].to_string();
-                    }
-                    7 => {
-                        s.project = (!parts[5].is_empty()).then(|| parts[5].to_string());
-                        s.name = parts[0].to_string();
-                    }
-                    6 => {
-                        s.project = (!parts[4].is_empty()).then(|| parts[4].to_string());
-                        s.name = parts[0].to_string();
-                    }
-                    _ => {
-                        error!(
-                            "{}: Expected 10, 9, 7 or 6 columns. Got {} columns: {:?}",
-                            self.name,
-                            parts.len(),
-                            parts
-                        );
-                        return Err(Box::from(
-                            "Expected 10 or 6 columns in [Data] section of sample sheet",
-                        ));
-                    }
-                }
-
-                parse_samplename(&mut s);
-                s.run = run_name.to_string();
-                if !s.name.is_empty() {
-                    self.samples.push( (s, Vec::new()) );
-                }
             }
+
+            let mut s = models::NewSample {
+                name: name.to_string(),
+                project: data.get(row, "Sample_Project")
+                    .or_else(|| projects.get(name).map(|p| p.as_str()))
+                    .map(String::from),
+                // if it parses as unsigned number and it's positive, it might be a
+                // LIMS id.
+                lims_id: data.get(row, "Description").and_then(|d| d.parse::<i64>().ok()).filter(|id| *id > 0),
+                // v1 sheets call the sequences index and index2, v2 sheets Index and Index2
+                i7_index_id: data.get(row, "I7_Index_ID").map(String::from),
+                i7_index: data.get(row, "Index").map(String::from),
+                i5_index_id: data.get(row, "I5_Index_ID").map(String::from),
+                i5_index: data.get(row, "Index2").map(String::from),
+                lane,
+                ..Default::default()
+            };
+
+            parse_samplename(&mut s);
+            s.run = run_name.to_string();
+            s.key = sample_key(&self.samples, run_name, &s.name);
+            self.samples.push( (s, Vec::new()) );
         }
-        
 
         let orig_num = fastqs.len();
-        let num = assign_fastqs(&mut self.samples, fastqs, run_name);
-        if num > 0 {
+        self.unmatched_fastqs = assign_fastqs(&mut self.samples, fastqs, run_name);
+        if !self.unmatched_fastqs.is_empty() {
             warn!(
-                "{}: {} of {} fastqs were not assigned to samples",
-                self.name, num, orig_num
+                "{}: {} of {} fastqs did not match the sample sheet",
+                self.name, self.unmatched_fastqs.len(), orig_num
             );
         }
 
+        for (_, files) in self.samples.iter().filter(|(s, _)| s.name == "Unknown") {
+            self.issues.push(Issue {
+                kind: IssueKind::UnknownSample,
+                message: format!("Cannot derive a sample name for {}", files.join(", ")),
+            });
+        }
+
         if self.samples.is_empty() {
             warn!("{}: Sample sheet for resulted in 0 samples", self.name);
+            self.issues.push(Issue { kind: IssueKind::NoSamples, message: String::from("Sample sheet resulted in 0 samples") });
         }
         Ok(())
     }
 
+    /// Looks for demultiplexing statistics among `files` (paths relative to the run
+    /// root) and assigns read counts to the samples. `read` returns the content of
+    /// a file.
+    ///
+    /// If there are several statistics, i.e. from repeated analyses, the one with
+    /// the preferred format and the last path is used.
+    fn load_stats(&mut self, files: &[String], mut read: impl FnMut(&str) -> Option<String>) {
+        let path = STATS_FILES.iter().find_map(|name| files
+            .iter()
+            .filter(|f| Path::new(f).file_name() == Some(name.as_ref()))
+            .max());
+        let path = match path {
+            Some(path) => path,
+            None => {
+                debug!("{}: No demultiplexing statistics found", self.name);
+                return;
+            }
+        };
+
+        let stats = match read(path) {
+            None => Err(Box::<dyn Error>::from("Cannot read file")),
+            Some(content) if path.ends_with("Demultiplex_Stats.csv") => {
+                let quality = read(&path.replace("Demultiplex_Stats.csv", "Quality_Metrics.csv"));
+                DemuxStats::from_bclconvert(&content, quality.as_deref())
+            },
+            Some(content) if path.ends_with("Stats.json") => DemuxStats::from_stats_json(&content),
+            Some(content) => DemuxStats::from_demultiplexing_xml(&content),
+        };
+
+        match stats {
+            Ok(stats) => {
+                for (s, _) in self.samples.iter_mut() {
+                    if let Some(counts) = stats.samples.get(&s.name) {
+                        s.reads = Some(counts.reads);
+                        s.bases = counts.bases;
+                        s.bases_q30 = counts.bases_q30;
+                    }
+                }
+                self.stats = Some(stats.total);
+            },
+            Err(e) => {
+                warn!("{}: Cannot parse {}: {}", self.name, path, e);
+                self.issues.push(Issue { kind: IssueKind::MalformedStats, message: format!("Cannot parse {}: {}", path, e) });
+            },
+        }
+    }
+
     /// Constructor delegation, will pick up run infos from a directory
     fn from_dir(path: &Path) -> Result<Self> {
         let run_name = path
@@ -413,33 +668,44 @@ impl Run {
             .as_os_str()
             .to_string_lossy();
 
-        let run_date = parse_date(&run_name);
+        let info = load_runinfo(&run_name, |f| std::fs::read_to_string(path.join(f)).ok());
 
         // make fastq file list
-        let walker = walkdir::WalkDir::new(&path).follow_links(true).into_iter();
-        let fastqs: Vec<String> = walker
-            .into_iter()
-            .map(|e| {
-                if let Ok(e) = e {
-                    if e.depth() > 1 {
-                        let s = e.path().display().to_string();
-                        // cut off the root directory. We only want fastq paths relative to the run root
-                        s[path.display().to_string().len() + 1..].to_string()
-                    } else {
-                        String::from("")
-                    }
-                } else {
-                    String::from("")
-                }
-            })
-            .filter(|e| is_fastq(e))
-            .collect();
+        let mut fastq_meta: HashMap<String, FastqMeta> = HashMap::new();
+        let mut stats_files: Vec<String> = Vec::new();
+        let walker = walkdir::WalkDir::new(&path).follow_links(true).min_depth(2).into_iter();
+        for e in walker.filter_map(|e| e.ok()) {
+            let s = e.path().display().to_string();
+            // cut off the root directory. We only want fastq paths relative to the run root
+            let s = s[path.display().to_string().len() + 1..].to_string();
+            if STATS_FILES.iter().any(|f| e.file_name() == *f) {
+                stats_files.push(s.clone());
+            }
+            if !is_fastq(&s) {
+                continue;
+            }
+            let meta = e.metadata().ok();
+            fastq_meta.insert(s, FastqMeta {
+                size: meta.as_ref().map(|m| m.len() as i64).unwrap_or_default(),
+                mtime: meta
+                    .and_then(|m| m.modified().ok())
+                    .map(file_mtime),
+                md5: None,
+                sha256: None,
+                reads: None,
+                mean_quality: None,
+            });
+        }
+        let fastqs: Vec<String> = fastq_meta.keys().cloned().collect();
         if fastqs.is_empty() {
             error!("No fastqs for {}?", run_name);
         }
 
         let mut r = Run {
-            date: run_date?,
+            date: match info.date {
+                Some(date) => date,
+                None => parse_date(&run_name)?,
+            },
             name: run_name.to_owned().to_string(),
             path: PathBuf::from(path),
             samples: Vec::new(),
@@ -447,6 +713,12 @@ impl Run {
             chemistry: String::from(""),
             description: String::from(""),
             investigator: String::from(""),
+            info,
+            stats: None,
+            unmatched_fastqs: Vec::new(),
+            fastq_meta,
+            cellsheet: CellsheetStatus::NotFound,
+            issues: Vec::new(),
         };
 
         let mut ss = path.to_owned();
@@ -456,7 +728,10 @@ impl Run {
             r.parse_samplesheet(&mut ssheet, fastqs, &run_name)?;
         } else {
             warn!("{}: No SampleSheet.csv found, skipping!", run_name);
+            r.unmatched_fastqs = fastqs;
+            r.issues.push(Issue { kind: IssueKind::MissingSamplesheet, message: String::from("No SampleSheet.csv found") });
         }
+        r.load_stats(&stats_files, |f| std::fs::read_to_string(path.join(f)).ok());
 
         Ok(r)
     }
@@ -466,10 +741,17 @@ impl Run {
         let mut z = ZipArchive::new(File::open(path)?)?;
         let run_name = path.file_stem().unwrap().to_string_lossy();
 
-        let run_date = parse_date(&run_name);
+        let info = load_runinfo(&run_name, |f| {
+            let mut content = String::new();
+            z.by_name(&format!("{}/{}", run_name, f)).ok()?.read_to_string(&mut content).ok()?;
+            Some(content)
+        });
 
         let mut r = Run {
-            date: run_date?,
+            date: match info.date {
+                Some(date) => date,
+                None => parse_date(&run_name)?,
+            },
             name: run_name.to_owned().to_string(),
             path: PathBuf::from(path),
             samples: Vec::new(),
@@ -477,20 +759,48 @@ impl Run {
             chemistry: String::from(""),
             description: String::from(""),
             investigator: String::from(""),
+            info,
+            stats: None,
+            unmatched_fastqs: Vec::new(),
+            fastq_meta: HashMap::new(),
+            cellsheet: CellsheetStatus::NotFound,
+            issues: Vec::new(),
         };
 
-        let fastqs: Vec<String> = z
-            .file_names()
-            .filter(|name| is_fastq(name))
-            .map(|n| n.to_string())
-            .collect();
+        for i in 0..z.len() {
+            let entry = z.by_index(i)?;
+            if is_fastq(entry.name()) {
+                r.fastq_meta.insert(entry.name().to_string(), FastqMeta {
+                    size: entry.size() as i64,
+                    mtime: zip_mtime(entry.last_modified()),
+                    md5: None,
+                    sha256: None,
+                    reads: None,
+                    mean_quality: None,
+                });
+            }
+        }
+        let fastqs: Vec<String> = r.fastq_meta.keys().cloned().collect();
 
         if let Ok(mut ssheet) = z.by_name(&format!("{}/SampleSheet.csv", run_name)) {
             r.parse_samplesheet(&mut ssheet, fastqs, &run_name)?;
         } else {
             warn!("{}: No SampleSheet.csv found, skipping!", run_name);
+            r.unmatched_fastqs = fastqs;
+            r.issues.push(Issue { kind: IssueKind::MissingSamplesheet, message: String::from("No SampleSheet.csv found") });
         }
 
+        let stats_files: Vec<String> = z
+            .file_names()
+            .filter(|name| STATS_FILES.iter().any(|f| Path::new(name).file_name() == Some(f.as_ref())))
+            .map(String::from)
+            .collect();
+        r.load_stats(&stats_files, |f| {
+            let mut content = String::new();
+            z.by_name(f).ok()?.read_to_string(&mut content).ok()?;
+            Some(content)
+        });
+
         Ok(r)
     }
 
@@ -507,11 +817,18 @@ impl Run {
         
         run.map(|mut r| {
             if let Some(csheet) = r.find_cellsheet(cellsheetdir) {
-                if let Err(e) = r.parse_cellsheet(&csheet) {
-                    warn!("{}: Found a cell sheet but could not parse it: {}", r.name, e);
-                } else {
-                    debug!("{}: Cell sheet imported", r.name);
-                }
+                let path = csheet.display().to_string();
+                r.cellsheet = match r.parse_cellsheet(&csheet) {
+                    Err(e) => {
+                        warn!("{}: Found a cell sheet but could not parse it: {}", r.name, e);
+                        r.issues.push(Issue { kind: IssueKind::MalformedCellsheet, message: format!("Cannot parse {}: {}", path, e) });
+                        CellsheetStatus::Failed { path, error: e.to_string() }
+                    },
+                    Ok(samples) => {
+                        debug!("{}: Cell sheet imported", r.name);
+                        CellsheetStatus::Imported { path, samples }
+                    }
+                };
             } else {
                 debug!("{}: No cell sheet found", r.name);
             }
@@ -519,6 +836,108 @@ impl Run {
         })
     }
 
+    /// Computes MD5 and SHA-256 of all FASTQs. Checksums from `known` are reused
+    /// if size and modification time of the file have not changed since.
+    ///
+    /// FASTQs that cannot be read are recorded as issues. Returns the number of
+    /// files that had to be read.
+    pub fn compute_checksums(&mut self, known: &HashMap<String, FastqMeta>) -> Result<usize> {
+        let mut zip = if self.path.is_dir() {
+            None
+        } else {
+            Some(ZipArchive::new(File::open(&self.path)?)?)
+        };
+
+        let mut computed = 0;
+        let mut filenames: Vec<String> = self.fastq_meta.keys().cloned().collect();
+        filenames.sort_unstable();
+        for filename in filenames {
+            let meta = self.fastq_meta.get_mut(&filename).unwrap();
+            if let Some(k) = known.get(&filename) {
+                if k.size == meta.size && k.mtime == meta.mtime && k.md5.is_some() && k.sha256.is_some() {
+                    meta.md5 = k.md5.clone();
+                    meta.sha256 = k.sha256.clone();
+                    continue;
+                }
+            }
+
+            let c = match zip.as_mut() {
+                Some(z) => z.by_name(&filename).map_err(Box::<dyn Error>::from)
+                    .and_then(|f| Ok(checksums(f)?)),
+                None => File::open(self.path.join(&filename)).map_err(Box::<dyn Error>::from)
+                    .and_then(|f| Ok(checksums(f)?)),
+            };
+            match c {
+                Ok(c) => {
+                    meta.md5 = Some(c.md5);
+                    meta.sha256 = Some(c.sha256);
+                    computed += 1;
+                },
+                Err(e) => {
+                    warn!("{}: Cannot compute checksums of {}: {}", self.name, filename, e);
+                    self.issues.push(Issue { kind: IssueKind::UnreadableFastq, message: format!("Cannot read {}: {}", filename, e) });
+                },
+            }
+        }
+        Ok(computed)
+    }
+
+    /// Counts reads and mean quality of all FASTQs in parallel. Counts from `known`
+    /// are reused if size and modification time of the file have not changed since.
+    ///
+    /// FASTQs that cannot be read are recorded as issues. Returns the number of
+    /// files that had to be read.
+    pub fn count_reads(&mut self, known: &HashMap<String, FastqMeta>) -> Result<usize> {
+        let mut todo: Vec<String> = Vec::new();
+        for (filename, meta) in self.fastq_meta.iter_mut() {
+            match known.get(filename) {
+                Some(k) if k.size == meta.size && k.mtime == meta.mtime && k.reads.is_some() => {
+                    meta.reads = k.reads;
+                    meta.mean_quality = k.mean_quality;
+                },
+                _ => todo.push(filename.clone()),
+            }
+        }
+        todo.sort_unstable();
+        let computed = todo.len();
+
+        // every thread needs its own handle on the zip file
+        let path = &self.path;
+        let is_zip = !path.is_dir();
+        let results: Vec<(String, std::result::Result<ReadStats, String>)> = todo
+            .into_par_iter()
+            .map_init(
+                || if is_zip { File::open(path).ok().and_then(|f| ZipArchive::new(f).ok()) } else { None },
+                |zip, filename| {
+                    let stats = if is_zip {
+                        match zip.as_mut() {
+                            Some(z) => z.by_name(&filename).map_err(|e| e.to_string())
+                                .and_then(|f| read_stats(f).map_err(|e| e.to_string())),
+                            None => Err(String::from("Cannot open zip file")),
+                        }
+                    } else {
+                        File::open(path.join(&filename)).and_then(read_stats).map_err(|e| e.to_string())
+                    };
+                    (filename, stats)
+                })
+            .collect();
+
+        for (filename, stats) in results {
+            match stats {
+                Ok(stats) => {
+                    let meta = self.fastq_meta.get_mut(&filename).unwrap();
+                    meta.reads = Some(stats.reads);
+                    meta.mean_quality = stats.mean_quality;
+                },
+                Err(e) => {
+                    warn!("{}: Cannot count reads of {}: {}", self.name, filename, e);
+                    self.issues.push(Issue { kind: IssueKind::UnreadableFastq, message: format!("Cannot read {}: {}", filename, e) });
+                },
+            }
+        }
+        Ok(computed)
+    }
+
     pub fn to_schema_run(&self) -> models::Run {
         models::Run {
              assay: self.assay.clone(),
@@ -528,6 +947,18 @@ impl Run {
             investigator: self.investigator.clone(),
             name: self.name.clone(),
             path: self.path.to_str().expect("Could not convert path to string").to_string(),
+            removed: None,
+            instrument: self.info.instrument.clone(),
+            flowcell: self.info.flowcell.clone(),
+            run_number: self.info.run_number,
+            read_structure: self.info.read_structure(),
+            reads: self.stats.map(|s| s.reads),
+            bases: self.stats.and_then(|s| s.bases),
+            bases_q30: self.stats.and_then(|s| s.bases_q30),
+            cellsheet: match &self.cellsheet {
+                CellsheetStatus::Imported { path, .. } => Some(path.clone()),
+                _ => None,
+            },
         }
     }
 }
@@ -548,4 +979,20 @@ mod tests {
         println!("Run: {:?}", r);
         Ok(())
     }
+
+    #[test]
+    fn fastq_name() {
+        assert_eq!(
+            parse_fastq_name("Data/Intensities/BaseCalls/D-21-01234_IGH_S12_L001_R2_001.fastq.gz"),
+            Some(FastqName { sample_number: 12, lane: Some(1), read: String::from("R2") })
+        );
+        assert_eq!(
+            parse_fastq_name("Sample_S3_I1_001.fastq.gz"),
+            Some(FastqName { sample_number: 3, lane: None, read: String::from("I1") })
+        );
+        assert_eq!(parse_fastq_name("Sample.fastq.gz"), None);
+        assert_eq!(single(vec![Some(1), Some(1)].into_iter()), Some(1));
+        assert_eq!(single(vec![Some(1), Some(2)].into_iter()), None);
+        assert_eq!(single(vec![Some(1), None].into_iter()), None);
+    }
 }
@@ -1,19 +1,25 @@
 //! This module contains tools to build sample sheets from lists of samples,
 //! and to export sample sheets to ARResT-compatible formats.
 
-use std::{collections::HashMap, convert::TryInto, fs::File, io::Write, path::{Path, PathBuf}};
+use std::{collections::HashMap, convert::TryInto, fs::File, io::{Read, Seek, Write}, path::{Path, PathBuf}};
 use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::checksum::HashingReader;
+use crate::zipstream::ZipStreamWriter;
 use crate::{models, vaultdb::MatchStatus};
 
 use calamine::{Reader, Xlsx, open_workbook};
-use diesel::{PgConnection, QueryDsl, RunQueryDsl, ExpressionMethods};
+use diesel::{PgConnection, QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
 
 /// A catch-all error type
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+/// Columns that every exported sample sheet starts with
+pub const BASIC_HEADER: [&str; 17] = ["Sample", "run", "DNA nr", "primer set", "project", "LIMS ID", "cells", "lane", "S number", "I7 index ID", "I7 index", "I5 index ID", "I5 index", "reads", "yield", "%Q30", "sample key"];
+
 /// A sample sheet containing a list of samples
 #[derive(Debug)]
 pub struct SampleSheet {
@@ -21,6 +27,16 @@ pub struct SampleSheet {
     pub entries: Vec<SampleSheetEntry>,
 }
 
+/// FASTQs of the entries of a sample sheet and the paths of their runs
+#[derive(Debug)]
+pub struct FastqSources {
+    /// Run paths by run name
+    runpaths: HashMap<String, String>,
+
+    /// FASTQs by index of the entry
+    files: Vec<Vec<models::Fastq>>,
+}
+
 /// An entry of a SampleSheet
 #[derive(Debug,Default)]
 pub struct SampleSheetEntry {
@@ -58,8 +74,8 @@ pub(crate) fn normalize_dna_nr(dnanr: &str) -> Option<String> {
     }
     Some(format!(
         "{:02}-{:05}",
-        parts[0].parse::<u32>().unwrap(),
-        parts[1].parse::<u32>().unwrap()
+        parts[0].parse::<u32>().ok()?,
+        parts[1].parse::<u32>().ok()?
     ))
 }
 
@@ -71,9 +87,17 @@ impl SampleSheetEntry {
         Ok(PathBuf::from(p))
     }
 
-    pub fn fastq_paths(&self, db: &PgConnection) -> Result<Vec<String>> {
+    pub fn fastqs(&self, db: &PgConnection) -> Result<Vec<models::Fastq>> {
         use crate::schema::fastq;
-        Ok(fastq::table.select(fastq::filename).filter(fastq::sample_id.eq(self.model.id)).load(db)?)
+        Ok(fastq::table.filter(fastq::sample_id.eq(self.model.id)).order(fastq::filename).load(db)?)
+    }
+
+    /// Percentage of bases with quality 30 or higher
+    pub fn q30(&self) -> Option<f64> {
+        match (self.model.bases_q30, self.model.bases) {
+            (Some(q30), Some(bases)) if bases > 0 => Some(q30 as f64 / bases as f64 * 100.0),
+            _ => None,
+        }
     }
 
     // generate a short but unique string representation of the run
@@ -102,40 +126,142 @@ impl From<Vec<models::Sample>> for SampleSheet {
     }
 }
 
-fn extract_from_zip(path: &Path, fastqs: &[String],  targetdir: &Path, sample_prefix: Option<String>) -> Result<()> {
-    let zipfile = std::fs::File::open(path)?;
-    let mut zip = zip::ZipArchive::new(zipfile)?;
-    let prefix = sample_prefix.unwrap_or_else(|| String::from(""));
+/// Name of an extracted FASTQ in the target directory
+fn target_name(fastq: &models::Fastq, prefix: &str) -> String {
+    prefix.to_string() + &PathBuf::from(&fastq.filename).file_name().unwrap().to_string_lossy()
+}
 
-    for f in fastqs {
-        let mut fastq = zip.by_name(f)?;
+/// Copies a FASTQ to `target` and verifies the copy against the size and checksums
+/// known from the database
+fn copy_verified<R: Read>(src: R, target: &Path, fastq: &models::Fastq) -> Result<()> {
+    let mut targetfile = std::fs::File::create(target)?;
+    let mut src = HashingReader::new(src);
+    std::io::copy(&mut src, &mut targetfile)?;
+    verify(src, fastq)
+}
 
-        let target = PathBuf::from(fastq.name());
-        let mut local_path = PathBuf::from(targetdir);
-        
-        local_path.push(prefix.clone() + &target.file_name().unwrap().to_string_lossy().to_string());
-        
-        let mut targetfile = std::fs::File::create(local_path)?;
-        std::io::copy(&mut fastq, &mut targetfile)?;
+/// Checks the data that has been read through `src` against the size and checksums
+/// known from the database
+fn verify<R: Read>(src: HashingReader<R>, fastq: &models::Fastq) -> Result<()> {
+    if let Some(size) = fastq.size {
+        if src.bytes_read() != size as u64 {
+            return Err(Box::from(format!("{}: copied {} bytes, expected {}", fastq.filename, src.bytes_read(), size)));
+        }
+    }
+    let checksums = src.finish();
+    if fastq.md5.as_ref().map(|md5| *md5 != checksums.md5).unwrap_or(false)
+        || fastq.sha256.as_ref().map(|sha256| *sha256 != checksums.sha256).unwrap_or(false) {
+        return Err(Box::from(format!("{}: checksum mismatch", fastq.filename)));
     }
     Ok(())
 }
 
-fn extract_from_dir(path: &Path, fastqs: &[String], targetdir: &Path, sample_prefix: Option<String>) -> Result<()> {
-    let prefix = sample_prefix.unwrap_or_else(|| String::from(""));
+/// Called with the target name of each FASTQ and the reason if it could not be extracted
+type Progress<'a> = &'a (dyn Fn(&str, Option<String>) + Sync);
 
+/// Copies each FASTQ with `copy`, reporting the outcome to `progress`. A failed FASTQ
+/// doesn't keep the remaining ones from being copied.
+fn extract_each<C: FnMut(&models::Fastq, &Path) -> Result<()>>(fastqs: &[models::Fastq], targetdir: &Path, prefix: &str, progress: Progress, mut copy: C) -> Result<()> {
+    let mut failed = 0;
     for f in fastqs {
-        let mut src = path.to_path_buf();
-        src.push(f);
-        
-        let mut target = PathBuf::from(targetdir);
-        target.push(prefix.clone() + &PathBuf::from(f).file_name().unwrap().to_string_lossy().to_string());
-
-        std::fs::copy(&src, &target)?;
+        let name = target_name(f, prefix);
+        let result = copy(f, &targetdir.join(&name));
+        if result.is_err() {
+            failed += 1;
+        }
+        progress(&name, result.err().map(|e| e.to_string()));
+    }
+    if failed > 0 {
+        return Err(Box::from(format!("{} of {} FASTQs could not be extracted", failed, fastqs.len())));
     }
     Ok(())
 }
 
+/// Reports all FASTQs as failed for the same reason
+fn fail_all(fastqs: &[models::Fastq], prefix: &str, progress: Progress, reason: &str) {
+    for f in fastqs {
+        progress(&target_name(f, prefix), Some(reason.to_string()));
+    }
+}
+
+fn extract_from_zip(path: &Path, fastqs: &[models::Fastq],  targetdir: &Path, prefix: &str, progress: Progress) -> Result<()> {
+    let zip = File::open(path).map_err(Box::<dyn Error>::from).and_then(|f| Ok(zip::ZipArchive::new(f)?));
+    let mut zip = match zip {
+        Ok(zip) => zip,
+        Err(e) => {
+            fail_all(fastqs, prefix, progress, &e.to_string());
+            return Err(e);
+        },
+    };
+
+    extract_each(fastqs, targetdir, prefix, progress, |f, target| {
+        copy_verified(zip.by_name(&f.filename)?, target, f)
+    })
+}
+
+fn extract_from_dir(path: &Path, fastqs: &[models::Fastq], targetdir: &Path, prefix: &str, progress: Progress) -> Result<()> {
+    extract_each(fastqs, targetdir, prefix, progress, |f, target| {
+        copy_verified(File::open(path.join(&f.filename))?, target, f)
+    })
+}
+
+/// A row of an imported sample sheet and the samples it matches
+pub struct ImportedRow {
+    /// Row number as shown by spreadsheet applications, i.e. 2 for the first row below the header
+    pub row: usize,
+
+    /// All columns of the row by their header
+    pub cols: HashMap<String, String>,
+
+    pub status: MatchStatus,
+}
+
+/// Matches the rows of the first worksheet of a workbook against the database.
+/// 
+/// Rows are identified by the `sample key` column of sheets exported by the vault,
+/// or else by `run`, `LIMS ID`, `DNA nr`, `primer set` and `Sample` as far as given.
+pub fn import_xlsx<RS: Read + Seek>(mut ss: Xlsx<RS>, db: &PgConnection) -> Result<Vec<ImportedRow>> {
+    let sheetname = ss.sheet_names().first().cloned().ok_or_else(|| Box::<dyn Error>::from("Workbook contains no sheets"))?;
+    let sheet = ss.worksheet_range(&sheetname).ok_or_else(|| Box::<dyn Error>::from(format!("Cannot read sheet {}", sheetname)))??;
+
+    let header_row: Vec<String> = sheet.rows().next().ok_or_else(|| Box::<dyn Error>::from("Sheet is empty"))?.iter().map(|d| d.to_string()).collect();
+    let col_dna_nr = header_row.iter().position(|c| *c == "DNA nr");
+    let col_lims_id = header_row.iter().position(|c| *c == "LIMS ID");
+    let col_sample = header_row.iter().position(|c| *c == "Sample");
+    let col_primer_set = header_row.iter().position(|c| *c == "primer set");
+    let col_run = header_row.iter().position(|c| *c == "run").ok_or_else(|| Box::<dyn Error>::from("Could not find required column 'run'"))?;
+    let col_key = header_row.iter().position(|c| *c == "sample key");
+
+    let mut result = Vec::new();
+    for (row_idx, row) in sheet.rows().skip(1).enumerate() {
+        let run = row[col_run].to_string();
+        let name = col_sample.map(|col| row[col].to_string());
+        let primer_set = col_primer_set.map(|col| row[col].to_string());
+        let lims_id = col_lims_id.map(|col| row[col].to_string().parse::<i64>().ok()).flatten();
+        let dna_nr = col_dna_nr.map(|col| row[col].to_string());            
+
+        // sheets exported by the vault carry the sample key, which identifies the sample exactly
+        let key = col_key.map(|col| row[col].to_string()).filter(|k| !k.is_empty());
+        let status = if let Some(key) = key {
+            use crate::schema::sample;
+            match sample::table.filter(sample::key.eq(&key)).first::<models::Sample>(db).optional()? {
+                Some(sample) => MatchStatus::One(sample),
+                None => MatchStatus::None(format!("Unknown sample key {}", key)),
+            }
+        } else {
+            crate::vaultdb::match_samples(db, lims_id, dna_nr, primer_set, name, run)?
+        };
+
+        result.push(ImportedRow {
+            row: row_idx + 2,
+            cols: header_row.iter().cloned().zip(row).map(|(header,data)| (header, data.to_string())).collect(),
+            status,
+        });
+    }
+
+    Ok(result)
+}
+
 impl SampleSheet {
     pub fn new() -> Self {
         SampleSheet {
@@ -145,34 +271,19 @@ impl SampleSheet {
 
     pub fn from_xlsx(xlsx: &str, db: &PgConnection) -> Result<Self> {
         // open Excel workbook
-        let mut ss: Xlsx<_> = open_workbook(xlsx)?;
-        let sheetname = ss.sheet_names()[0].clone();
-        let sheet = ss.worksheet_range(&sheetname).unwrap()?;
-
-        let header_row: Vec<String> = sheet.rows().next().unwrap().iter().map(|d| d.to_string()).collect();
-        let col_dna_nr = header_row.iter().position(|c| *c == "DNA nr");
-        let col_lims_id = header_row.iter().position(|c| *c == "LIMS ID");
-        let col_sample = header_row.iter().position(|c| *c == "Sample");
-        let col_primer_set = header_row.iter().position(|c| *c == "primer set");
-        let col_run = header_row.iter().position(|c| *c == "run").ok_or_else(|| Box::<dyn Error>::from("Could not find required column 'run'"))?;
+        let ss: Xlsx<_> = open_workbook(xlsx)?;
 
         let mut result = SampleSheet::new();
-        for (row_idx, row) in sheet.rows().skip(1).enumerate() {
-            let run = row[col_run].to_string();
-            let name = col_sample.map(|col| row[col].to_string());
-            let primer_set = col_primer_set.map(|col| row[col].to_string());
-            let lims_id = col_lims_id.map(|col| row[col].to_string().parse::<i64>().ok()).flatten();
-            let dna_nr = col_dna_nr.map(|col| row[col].to_string());            
-
-            let mut entry: SampleSheetEntry = match crate::vaultdb::match_samples(db, lims_id, dna_nr, primer_set, name, run)? {
-                MatchStatus::None(reason) => { warn!("Cannot find match for sample in row {}. Skipping. Reason: {}", row_idx+2, reason); continue }
+        for row in import_xlsx(ss, db)? {
+            let mut entry: SampleSheetEntry = match row.status {
+                MatchStatus::None(reason) => { warn!("Cannot find match for sample in row {}. Skipping. Reason: {}", row.row, reason); continue }
                 MatchStatus::One(sample) => sample.into(),
-                MatchStatus::Multiple(v) => { warn!("Found {} matches for sample in row {}. Skipping.", row_idx+2, v.len()); continue }
+                MatchStatus::Multiple(v) => { warn!("Found {} matches for sample in row {}. Skipping.", v.len(), row.row); continue }
             };
 
             // put all sample sheet columns as extra columns. During export, the user may select which one to use.
             // Defaults to what the DB already knows
-            entry.extra_cols = header_row.iter().cloned().zip(row).map(|(header,data)| (header, data.to_string())).collect();
+            entry.extra_cols = row.cols;
             
             result.entries.push(entry);
         }
@@ -180,11 +291,26 @@ impl SampleSheet {
         Ok(result)
     }
 
+    /// Builds a sample sheet from samples and the sample sheet columns imported for them,
+    /// given by sample key
+    pub fn with_extra_cols(samples: Vec<models::Sample>, extra_cols: &HashMap<String, HashMap<String, String>>) -> Self {
+        SampleSheet {
+            entries: samples
+                .into_iter()
+                .map(|s| {
+                    let extra_cols = extra_cols.get(&s.key).cloned().unwrap_or_default();
+                    SampleSheetEntry { model: s, extra_cols }
+                })
+                .collect()
+        }
+    }
+
     pub fn has_multiple_runs(&self) -> bool {
         self.entries.iter().map(|e| (e.model.run.clone(), true)).collect::<HashMap<String,bool>>().into_keys().count() > 1
     }
 
-    pub fn extract_fastqs(&self, db: &PgConnection, targetpath: &Path) -> Result<()> {
+    /// Looks up the FASTQs of all entries and the paths of their runs
+    pub fn fastq_sources(&self, db: &PgConnection) -> Result<FastqSources> {
         // Make a list of paths that correspond to the runs so we can aggregate the ZIP extractions by ZIP file/run path
         let mut runs: Vec<&str> = self.entries.iter().map( |e| e.model.run.as_ref()).collect();
         runs.sort_unstable();
@@ -196,42 +322,151 @@ impl SampleSheet {
             run::table
                 .select((run::name, run::path))
                 .filter(run::name.eq_any(&runs))
-                .load(db)
-                .expect("Could not get run")
+                .load(db)?
         }.into_iter().collect();
 
+        let files: Vec<Vec<models::Fastq>> = self.entries.iter().map(|e| e.fastqs(db)).collect::<Result<_>>()?;
+        Ok(FastqSources { runpaths, files })
+    }
+
+    /// Prefix of the extracted FASTQs of an entry, to keep samples of different runs apart
+    fn prefix(&self, entry: &SampleSheetEntry) -> String {
+        if self.has_multiple_runs() { format!("{}-", entry.get_unique_run_id()) } else { String::new() }
+    }
+
+    /// All FASTQs of the entries with their names after extraction
+    pub fn targets<'a>(&self, sources: &'a FastqSources) -> Vec<(String, &'a models::Fastq)> {
+        self.entries
+            .iter()
+            .zip(&sources.files)
+            .flat_map(|(entry, files)| {
+                let prefix = self.prefix(entry);
+                files.iter().map(move |f| (target_name(f, &prefix), f))
+            })
+            .collect()
+    }
+
+    /// Checksums of the extracted FASTQs in the format understood by `md5sum -c` and `sha256sum -c`
+    fn checksum_files(&self, sources: &FastqSources) -> (String, String) {
+        let mut md5sums = String::new();
+        let mut sha256sums = String::new();
+        for (name, f) in self.targets(sources) {
+            if let Some(md5) = &f.md5 {
+                md5sums += &format!("{}  {}\n", md5, name);
+            }
+            if let Some(sha256) = &f.sha256 {
+                sha256sums += &format!("{}  {}\n", sha256, name);
+            }
+        }
+        (md5sums, sha256sums)
+    }
+
+    pub fn extract_fastqs(&self, db: &PgConnection, targetpath: &Path) -> Result<()> {
         // Collect run paths before we go into parallel extraction
-        let files: Vec<Vec<String>> = self.entries.iter().map(|e| e.fastq_paths(db)).collect::<Result<_>>()?;
- 
+        let sources = self.fastq_sources(db)?;
+        self.extract_fastqs_with_progress(&sources, targetpath, |_, _| ())
+    }
+
+    /// Extracts FASTQs like `extract_fastqs`, calling `progress` with the target name of each
+    /// FASTQ and the reason if it could not be extracted as soon as it is done
+    pub fn extract_fastqs_with_progress<F: Fn(&str, Option<String>) + Sync>(&self, sources: &FastqSources, targetpath: &Path, progress: F) -> Result<()> {
         // Extract FASTQs from runs sample-wise in parallel, adding a sample prefix on-the-fly
-        self.entries.par_iter().enumerate().for_each(|(idx, entry)| {
-            let runpath = PathBuf::from(runpaths.get(&entry.model.run).unwrap());
-            let fastqs = &files[idx];
-            let prefix = if runs.len() > 1 { Some( format!("{}-", entry.get_unique_run_id()) ) } else { None };
+        let failed = self.entries.par_iter().enumerate().filter(|(idx, entry)| {
+            let fastqs = &sources.files[*idx];
+            let prefix = self.prefix(entry);
+            let runpath = match sources.runpaths.get(&entry.model.run) {
+                Some(p) => PathBuf::from(p),
+                None => {
+                    error!("Run {} is unknown, skipping.", entry.model.run);
+                    fail_all(fastqs, &prefix, &progress, "Unknown run");
+                    return true
+                }
+            };
 
             if let Some(ext) = runpath.extension() {
                 if ext.to_ascii_lowercase() == "zip" {
-                    extract_from_zip(&runpath, fastqs.as_ref(), targetpath, prefix).unwrap_or_else(|e| {
+                    extract_from_zip(&runpath, fastqs.as_ref(), targetpath, &prefix, &progress).map_err(|e| {
                         error!("Cannot extract from zip file {}: {}", runpath.display(), e)
-                    });
+                    }).is_err()
                 } else {
                     warn!(
                         "Run path {} has weird extension. Don't know what to do, skipping.",
                         entry.model.run
                     );
+                    fail_all(fastqs, &prefix, &progress, "Run path has an unknown extension");
+                    true
                 }
             } else {
-                extract_from_dir(&runpath, fastqs.as_ref(), targetpath, prefix)
-                    .unwrap_or_else(|e| error!("Cannot copy from run folder: {}", e));
+                extract_from_dir(&runpath, fastqs.as_ref(), targetpath, &prefix, &progress)
+                    .map_err(|e| error!("Cannot copy from run folder: {}", e))
+                    .is_err()
             }
-        });
+        }).count();
+
+        // ship checksums in the format understood by md5sum -c and sha256sum -c
+        let (md5sums, sha256sums) = self.checksum_files(sources);
+        if !md5sums.is_empty() {
+            File::create(targetpath.join("md5sums.txt"))?.write_all(md5sums.as_bytes())?;
+        }
+        if !sha256sums.is_empty() {
+            File::create(targetpath.join("sha256sums.txt"))?.write_all(sha256sums.as_bytes())?;
+        }
+
+        if failed > 0 {
+            return Err(Box::from(format!("FASTQs of {} of {} samples could not be extracted", failed, self.entries.len())));
+        }
         Ok(())
     }
 
+    /// Writes a zip archive with the FASTQs of all entries, named as by `extract_fastqs`, their
+    /// checksums and the sample sheet in TSV format to `out`. FASTQs are read one by one from
+    /// the run folders or zip files, so nothing is kept in memory or on disk.
+    pub fn write_zip<W: Write, T: AsRef<str> + PartialEq>(&self, sources: &FastqSources, overrides: &[T], out: W) -> Result<W> {
+        let mut zip = ZipStreamWriter::new(out);
+        // run zip files are opened once for all of their samples
+        let mut archives: HashMap<&str, zip::ZipArchive<File>> = HashMap::new();
+
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let run = entry.model.run.as_str();
+            let runpath = PathBuf::from(sources.runpaths.get(run).ok_or_else(|| format!("Run {} is unknown", run))?);
+            let prefix = self.prefix(entry);
+
+            for f in &sources.files[idx] {
+                let name = target_name(f, &prefix);
+                match runpath.extension() {
+                    Some(ext) if ext.eq_ignore_ascii_case("zip") => {
+                        if !archives.contains_key(run) {
+                            archives.insert(run, zip::ZipArchive::new(File::open(&runpath)?)?);
+                        }
+                        let archive = archives.get_mut(run).unwrap();
+                        let mut src = HashingReader::new(archive.by_name(&f.filename)?);
+                        zip.add(&name, &mut src)?;
+                        verify(src, f)?;
+                    },
+                    Some(_) => return Err(Box::from(format!("Run path {} has weird extension", runpath.display()))),
+                    None => {
+                        let mut src = HashingReader::new(File::open(runpath.join(&f.filename))?);
+                        zip.add(&name, &mut src)?;
+                        verify(src, f)?;
+                    },
+                }
+            }
+        }
 
-    pub fn write_csv<T: AsRef<str> + PartialEq> (&self, separator: &str, overrides: &[T], outfile: &Path) -> Result<()> {
-        let basic_header = vec!["Sample", "run", "DNA nr", "primer set", "project", "LIMS ID", "cells"];
-        
+        zip.add("samplesheet.tsv", self.to_csv("\t", overrides).as_bytes())?;
+        let (md5sums, sha256sums) = self.checksum_files(sources);
+        if !md5sums.is_empty() {
+            zip.add("md5sums.txt", md5sums.as_bytes())?;
+        }
+        if !sha256sums.is_empty() {
+            zip.add("sha256sums.txt", sha256sums.as_bytes())?;
+        }
+        Ok(zip.finish()?)
+    }
+
+    /// Header of the exported sample sheet: the basic columns, followed by all imported
+    /// extra columns that are not basic columns, in alphabetical order
+    pub fn header(&self) -> Vec<String> {
         // extra_cols hashmap is not necessarily fully populated for every sample, so check all
         let mut all_headers: Vec<String> = self.entries
                 .iter()
@@ -242,146 +477,155 @@ impl SampleSheet {
         all_headers.dedup();
 
         //...to not have duplicates in the header lines where extra_cols and the basic headers would overlap
-        let all_sans_basic: Vec<&str> = all_headers.iter().filter(|&h| !basic_header.contains(&(**h).as_ref())).map(|s| s.as_ref()).collect();
-
-        // write header
-        let mut csv = basic_header.join(separator);
-        if !all_sans_basic.is_empty() {
-            csv += separator;
-            csv += &all_sans_basic.join(separator);
-        }
-        csv += "\n";
+        BASIC_HEADER.iter()
+            .map(|h| h.to_string())
+            .chain(all_headers.into_iter().filter(|h| !BASIC_HEADER.contains(&h.as_str())))
+            .collect()
+    }
 
+    /// Rows of the exported sample sheet in the order of `header()`. Basic columns listed
+    /// in `overrides` are taken from the imported extra columns instead of the database.
+    pub fn rows<T: AsRef<str> + PartialEq>(&self, overrides: &[T]) -> Vec<Vec<String>> {
+        let header = self.header();
         let has_multiple_runs = self.has_multiple_runs();
 
-        for e in &self.entries {
-            // write basic data points
-            for (col_idx, col) in basic_header.iter().enumerate() {
-                let last = col_idx+1 == basic_header.len();
-                if overrides.iter().any(|x| &x.as_ref() == col) {
-                    csv += e.extra_cols.get(*col).unwrap_or(&String::from(""));
-                } else {
-                    match *col {
-                        "Sample" => { 
-                            if has_multiple_runs {
-                                csv += &format!("{}-{}", e.get_unique_run_id(), e.model.name);
-                            } else {
-                                csv += &e.model.name; 
-                            }
-                        },
-                        "run" => { csv += &e.model.run; },
-                        "DNA nr" => { csv += &e.model.dna_nr.as_ref().unwrap_or(&String::from("")); },
-                        "primer set" => { csv += e.model.primer_set.as_ref().unwrap_or(&String::from("")); },
-                        "project" => { csv += &e.model.project.as_ref().unwrap_or(&String::from("")); },
-                        "LIMS ID" => { csv += &e.model.lims_id.map(|i| i.to_string()).unwrap_or_else(|| String::from("")); },
-                        "cells" => { 
-                            if let Some(cells) = e.model.cells.as_ref() {
-                                csv += &cells.to_string()
-                            } else if let Some(cells) = e.extra_cols.get(*col) {
-                                csv += cells
-                            }
-                            
-                        },
-                        s=> { error!("Unknown header: {}", s); panic!("Matching unknown basic header?!") },
-                    }
-                };
-                if !last {
-                    csv += separator;
-                }
-            }
-
-            if !all_sans_basic.is_empty() {
-                csv += separator;
+        self.entries.iter().map(|e| header.iter().map(|col| {
+            if !BASIC_HEADER.contains(&col.as_str()) || overrides.iter().any(|x| x.as_ref() == col.as_str()) {
+                return e.extra_cols.get(col).cloned().unwrap_or_default();
             }
-
-            // write non-basic columns (extra cols from sample sheet)
-            for (col_idx, col) in all_sans_basic.iter().enumerate() {
-                csv += e.extra_cols.get(*col).unwrap_or(&String::from(""));
-                if col_idx+1 < all_sans_basic.len() {
-                    csv += separator;
-                }
+            match col.as_str() {
+                "Sample" => { 
+                    if has_multiple_runs {
+                        format!("{}-{}", e.get_unique_run_id(), e.model.name)
+                    } else {
+                        e.model.name.to_string()
+                    }
+                },
+                "run" => { e.model.run.to_string() },
+                "DNA nr" => { e.model.dna_nr.clone().unwrap_or_default() },
+                "primer set" => { e.model.primer_set.clone().unwrap_or_default() },
+                "project" => { e.model.project.clone().unwrap_or_default() },
+                "LIMS ID" => { e.model.lims_id.map(|i| i.to_string()).unwrap_or_default() },
+                "cells" => { 
+                    if let Some(cells) = e.model.cells.as_ref() {
+                        cells.to_string()
+                    } else {
+                        e.extra_cols.get(col).cloned().unwrap_or_default()
+                    }
+                },
+                "lane" => { e.model.lane.map(|l| l.to_string()).unwrap_or_default() },
+                "S number" => { e.model.sample_number.map(|n| n.to_string()).unwrap_or_default() },
+                "I7 index ID" => { e.model.i7_index_id.clone().unwrap_or_default() },
+                "I7 index" => { e.model.i7_index.clone().unwrap_or_default() },
+                "I5 index ID" => { e.model.i5_index_id.clone().unwrap_or_default() },
+                "I5 index" => { e.model.i5_index.clone().unwrap_or_default() },
+                "reads" => { e.model.reads.map(|r| r.to_string()).unwrap_or_default() },
+                "yield" => { e.model.bases.map(|b| b.to_string()).unwrap_or_default() },
+                "%Q30" => { e.q30().map(|q| format!("{:.1}", q)).unwrap_or_default() },
+                "sample key" => { e.model.key.to_string() },
+                s=> { error!("Unknown header: {}", s); panic!("Matching unknown basic header?!") },
             }
+        }).collect()).collect()
+    }
 
+    /// The sample sheet in CSV format, with columns separated by `separator`
+    pub fn to_csv<T: AsRef<str> + PartialEq>(&self, separator: &str, overrides: &[T]) -> String {
+        let mut csv = self.header().join(separator);
+        csv += "\n";
+        for row in self.rows(overrides) {
+            csv += &row.join(separator);
             csv += "\n";
         }
-        
-        File::create(outfile)?.write_all(csv.as_bytes())?;
+        csv
+    }
+
+    pub fn write_csv<T: AsRef<str> + PartialEq> (&self, separator: &str, overrides: &[T], outfile: &Path) -> Result<()> {
+        File::create(outfile)?.write_all(self.to_csv(separator, overrides).as_bytes())?;
         Ok(())
     }
 
     pub fn write_xlsx<T: AsRef<str> + PartialEq> (&self, overrides: &[T], outfile: &Path) -> Result<()> {
+        // set up an empty file
+        let workbook = xlsxwriter::Workbook::new(outfile.to_str().ok_or_else(|| Box::<dyn Error>::from("Invalid file name"))?);
+        {
+            let mut sheet = workbook.add_worksheet(None)?;
+            
+            // write header
+            for (col, title) in self.header().iter().enumerate() {
+                sheet.write_string(0, col.clamp(0, u16::MAX.into()) as u16, title, None)?;
+            }
 
-        let basic_header = vec!["Sample", "run", "DNA nr", "primer set", "project", "LIMS ID", "cells"];
+            for (row, values) in self.rows(overrides).iter().enumerate() {
+                let row: u32 = (row + 1).try_into()?;
+                for (col_idx, val) in values.iter().enumerate() {
+                    let col_idx: u16 = col_idx.try_into()?;
+                    sheet.write_string(row, col_idx, val, None)?;
+                }
+            }
+        }
+        workbook.close()?;
         
-        // extra_cols hashmap is not necessarily fully populated for every sample, so check all
-        let mut all_headers: Vec<String> = self.entries
-                .iter()
-                .map::<Vec<String>,_>(|e| e.extra_cols.keys().cloned().collect())
-                .flatten()
-                .collect();
-        all_headers.sort_unstable();
-        all_headers.dedup();
+        Ok(())
+    }
 
-        //...to not have duplicates in the header lines where extra_cols and the basic headers would overlap
-        let all_sans_basic: Vec<&str> = all_headers.iter().filter(|&h| !basic_header.contains(&(**h).as_ref())).map(|s| s.as_ref()).collect();
+    /// The sample sheet in XLSX format. xlsxwriter can only write files, so the
+    /// workbook takes a detour through the temporary directory.
+    pub fn to_xlsx<T: AsRef<str> + PartialEq>(&self, overrides: &[T]) -> Result<Vec<u8>> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let tmp = std::env::temp_dir().join(format!("vault-samplesheet-{}-{}.xlsx", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
 
-        // set up an empty file
-        let workbook = xlsxwriter::Workbook::new(outfile.to_str().unwrap());
-        let mut sheet = workbook.add_worksheet(None)?;
-        
-        // write header
-        for (col, title) in basic_header.iter().chain(all_sans_basic.iter()).enumerate() {
-            sheet.write_string(0, col.clamp(0, u16::MAX.into()) as u16, title, None)?;
+        let result = self.write_xlsx(overrides, &tmp).and_then(|_| Ok(std::fs::read(&tmp)?));
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            warn!("Cannot remove temporary file {}: {}", tmp.display(), e);
         }
-        let has_multiple_runs = self.has_multiple_runs();
-
-        for (row, e) in self.entries.iter().enumerate() {
-            let row: u32 = (row + 1).try_into().unwrap();
-            // write basic data points
-            for (col_idx, colname) in basic_header.iter().enumerate() {
-                let col_idx: u16 = col_idx.try_into().unwrap();
-                
-                let val = if overrides.iter().any(|x| &x.as_ref() == colname) {
-                    e.extra_cols.get(*colname).unwrap_or(&String::from("")).to_string()
-                } else {
-                    match *colname {
-                        "Sample" => { 
-                            if has_multiple_runs {
-                                format!("{}-{}", e.get_unique_run_id(), e.model.name)
-                            } else {
-                                e.model.name.to_string()
-                            }
-                        },
-                        "run" => { e.model.run.to_string() },
-                        "DNA nr" => { e.model.dna_nr.as_ref().map(|s| s.clone()).unwrap_or(String::from("")) },
-                        "primer set" => { e.model.primer_set.as_ref().unwrap_or(&String::from("")).to_string() },
-                        "project" => { e.model.project.as_ref().map(|s| s.clone()).unwrap_or(String::from("")) },
-                        "LIMS ID" => { e.model.lims_id.map(|i| i.to_string()).unwrap_or_else(|| String::from("")) },
-                        "cells" => { 
-                            if let Some(cells) = e.model.cells.as_ref() {
-                                cells.to_string()
-                            } else if let Some(cells) = e.extra_cols.get(*colname) {
-                                cells.to_string()
-                            } else {
-                                String::from("")
-                            }
-                            
-                        },
-                        s=> { error!("Unknown header: {}", s); panic!("Matching unknown basic header?!") },
-                    }
-                };
+        result
+    }
+}
 
-                sheet.write_string(row, col_idx, &val, None)?;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(run: &str, name: &str, cells: Option<i32>) -> models::Sample {
+        models::Sample {
+            run: run.to_string(),
+            name: name.to_string(),
+            key: format!("{}/{}", run, name),
+            cells,
+            ..Default::default()
+        }
+    }
 
+    #[test]
+    fn overrides() {
+        let mut extra_cols = HashMap::new();
+        extra_cols.insert(String::from("210401_M00001_0001_000000000-ABCDE/A"), [("cells", "500"), ("patient", "P1")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        let ss = SampleSheet::with_extra_cols(vec![
+            sample("210401_M00001_0001_000000000-ABCDE", "A", Some(1000)),
+            sample("210401_M00001_0001_000000000-ABCDE", "B", None),
+        ], &extra_cols);
+
+        let header = ss.header();
+        assert_eq!(header.len(), BASIC_HEADER.len() + 1);
+        assert_eq!(header.last().map(String::as_str), Some("patient"));
+        let cells = header.iter().position(|h| h == "cells").unwrap();
+
+        let rows = ss.rows::<&str>(&[]);
+        assert_eq!(rows[0][cells], "1000");
+        assert_eq!(rows[0][BASIC_HEADER.len()], "P1");
+        assert_eq!(rows[1][BASIC_HEADER.len()], "");
+
+        let rows = ss.rows(&["cells"]);
+        assert_eq!(rows[0][cells], "500");
+        assert_eq!(rows[1][cells], "");
+    }
 
-            // write non-basic columns (extra cols from sample sheet)
-            for (col_idx, col) in all_sans_basic.iter().enumerate() {
-                let col_idx: u16 = (basic_header.len() + col_idx).try_into().unwrap();
-                sheet.write_string(row, col_idx, e.extra_cols.get(*col).unwrap_or(&String::from("")), None)?;
-            }
-        }
-        
-        Ok(())
+    #[test]
+    fn dna_nr() {
+        assert_eq!(normalize_dna_nr("D-21-345"), Some(String::from("21-00345")));
+        assert_eq!(normalize_dna_nr("21-12345"), Some(String::from("21-12345")));
+        assert_eq!(normalize_dna_nr("D-21-%"), None);
+        assert_eq!(normalize_dna_nr("none"), None);
     }
 }
@@ -1,7 +1,40 @@
+table! {
+    extract_file (id) {
+        id -> Int4,
+        job_id -> Int4,
+        filename -> Text,
+        size -> Nullable<Int8>,
+        status -> Varchar,
+        message -> Nullable<Text>,
+    }
+}
+
+table! {
+    extract_job (id) {
+        id -> Int4,
+        basket_id -> Int4,
+        target -> Text,
+        content -> Text,
+        status -> Varchar,
+        created -> Timestamp,
+        started -> Nullable<Timestamp>,
+        finished -> Nullable<Timestamp>,
+        message -> Nullable<Text>,
+    }
+}
+
 table! {
     fastq (sample_id, filename) {
         filename -> Varchar,
         sample_id -> Int4,
+        read -> Nullable<Varchar>,
+        lane -> Nullable<Int4>,
+        size -> Nullable<Int8>,
+        mtime -> Nullable<Timestamp>,
+        md5 -> Nullable<Varchar>,
+        sha256 -> Nullable<Varchar>,
+        reads -> Nullable<Int8>,
+        mean_quality -> Nullable<Float8>,
     }
 }
 
@@ -14,6 +47,32 @@ table! {
         description -> Nullable<Varchar>,
         investigator -> Varchar,
         path -> Text,
+        removed -> Nullable<Timestamp>,
+        instrument -> Nullable<Varchar>,
+        flowcell -> Nullable<Varchar>,
+        run_number -> Nullable<Int4>,
+        read_structure -> Nullable<Varchar>,
+        reads -> Nullable<Int8>,
+        bases -> Nullable<Int8>,
+        bases_q30 -> Nullable<Int8>,
+        cellsheet -> Nullable<Text>,
+    }
+}
+
+table! {
+    run_failure (path) {
+        path -> Text,
+        error -> Text,
+        failed -> Timestamp,
+    }
+}
+
+table! {
+    run_issue (id) {
+        id -> Int4,
+        run -> Varchar,
+        kind -> Varchar,
+        message -> Text,
     }
 }
 
@@ -27,6 +86,16 @@ table! {
         primer_set -> Nullable<Varchar>,
         id -> Int4,
         cells -> Nullable<Int4>,
+        key -> Varchar,
+        i7_index_id -> Nullable<Varchar>,
+        i7_index -> Nullable<Varchar>,
+        i5_index_id -> Nullable<Varchar>,
+        i5_index -> Nullable<Varchar>,
+        lane -> Nullable<Int4>,
+        sample_number -> Nullable<Int4>,
+        reads -> Nullable<Int8>,
+        bases -> Nullable<Int8>,
+        bases_q30 -> Nullable<Int8>,
     }
 }
 
@@ -35,15 +104,34 @@ table! {
         id -> Int4,
         created -> Timestamp,
         basket -> Text,
+        name -> Text,
+        modified -> Timestamp,
+    }
+}
+
+table! {
+    update_log (id) {
+        id -> Int4,
+        started -> Timestamp,
+        finished -> Timestamp,
+        report -> Text,
     }
 }
 
+joinable!(extract_file -> extract_job (job_id));
+joinable!(extract_job -> samplesheet (basket_id));
 joinable!(fastq -> sample (sample_id));
+joinable!(run_issue -> run (run));
 joinable!(sample -> run (run));
 
 allow_tables_to_appear_in_same_query!(
+    extract_file,
+    extract_job,
     fastq,
     run,
+    run_failure,
+    run_issue,
     sample,
     samplesheet,
+    update_log,
 );
@@ -0,0 +1,213 @@
+//! Parsers for demultiplexing statistics, giving read counts, yield and quality
+//! per sample.
+//!
+//! Supported are the outputs of bcl2fastq (`Stats/Stats.json`,
+//! `Stats/DemultiplexingStats.xml`) and BCL Convert (`Reports/Demultiplex_Stats.csv`
+//! with an optional `Reports/Quality_Metrics.csv` next to it).
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde_json::Value;
+
+use crate::illumina::{parse_csv, Table};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// File names of the supported statistics, in order of preference
+pub const STATS_FILES: [&str; 3] = ["Demultiplex_Stats.csv", "Stats.json", "DemultiplexingStats.xml"];
+
+/// Read counts and yield of a sample or a whole run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    /// Number of reads (clusters) passing filter
+    pub reads: i64,
+    /// Yield in bases, if known
+    pub bases: Option<i64>,
+    /// Bases with a quality score of 30 or higher, if known
+    pub bases_q30: Option<i64>,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.reads += other.reads;
+        self.bases = add_opt(self.bases, other.bases);
+        self.bases_q30 = add_opt(self.bases_q30, other.bases_q30);
+    }
+}
+
+fn add_opt(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Demultiplexing statistics of a run, summed up over all lanes
+#[derive(Debug, Default, PartialEq)]
+pub struct DemuxStats {
+    /// Counts by sample ID
+    pub samples: HashMap<String, Counts>,
+    /// Counts of the whole run, including undetermined reads
+    pub total: Counts,
+}
+
+impl DemuxStats {
+    fn add_sample(&mut self, sample: &str, counts: &Counts) {
+        self.samples.entry(sample.to_string()).or_default().add(counts);
+    }
+
+    /// Parses `Stats.json` as written by bcl2fastq
+    pub fn from_stats_json(json: &str) -> Result<Self> {
+        let stats: Value = serde_json::from_str(json)?;
+        let mut result = DemuxStats::default();
+
+        let lanes = stats["ConversionResults"].as_array().ok_or("No ConversionResults in Stats.json")?;
+        for lane in lanes {
+            for demux in lane["DemuxResults"].as_array().into_iter().flatten() {
+                let sample = demux["SampleId"].as_str().ok_or("DemuxResults without SampleId")?;
+                result.add_sample(sample, &json_counts(demux));
+            }
+            result.total.add(&Counts {
+                reads: lane["TotalClustersPF"].as_i64().unwrap_or_default(),
+                bases: lane["Yield"].as_i64(),
+                bases_q30: None,
+            });
+            // Q30 is only reported per sample and for undetermined reads
+            let mut q30 = json_counts(&lane["Undetermined"]).bases_q30;
+            for demux in lane["DemuxResults"].as_array().into_iter().flatten() {
+                q30 = add_opt(q30, json_counts(demux).bases_q30);
+            }
+            result.total.bases_q30 = add_opt(result.total.bases_q30, q30);
+        }
+        Ok(result)
+    }
+
+    /// Parses `DemultiplexingStats.xml` as written by bcl2fastq. It only has read counts.
+    pub fn from_demultiplexing_xml(xml: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let mut result = DemuxStats::default();
+
+        for project in doc.descendants().filter(|n| n.has_tag_name("Project")) {
+            for sample in project.children().filter(|n| n.has_tag_name("Sample")) {
+                let barcode = match sample.children().find(|n| n.has_tag_name("Barcode") && n.attribute("name") == Some("all")) {
+                    Some(b) => b,
+                    None => continue,
+                };
+                let reads: i64 = barcode
+                    .descendants()
+                    .filter(|n| n.has_tag_name("BarcodeCount"))
+                    .filter_map(|n| n.text()?.trim().parse::<i64>().ok())
+                    .sum();
+                let counts = Counts { reads, ..Default::default() };
+
+                match (project.attribute("name"), sample.attribute("name")) {
+                    (Some("all"), Some("all")) => result.total = counts,
+                    (Some("all"), _) | (_, Some("all")) | (_, Some("Undetermined")) => {},
+                    (_, Some(name)) => result.add_sample(name, &counts),
+                    _ => {},
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Parses `Demultiplex_Stats.csv` and optionally `Quality_Metrics.csv` as written
+    /// by BCL Convert
+    pub fn from_bclconvert(demux_csv: &str, quality_csv: Option<&str>) -> Result<Self> {
+        let demux = csv_table(demux_csv);
+        if demux.column("SampleID").is_none() || demux.column("# Reads").is_none() {
+            return Err(Box::from("Demultiplex_Stats.csv lacks SampleID or # Reads column"));
+        }
+
+        let mut result = DemuxStats::default();
+        for row in demux.rows.iter() {
+            let sample = demux.get(row, "SampleID").unwrap_or_default();
+            let counts = Counts {
+                reads: demux.get(row, "# Reads").and_then(|r| r.parse().ok()).unwrap_or_default(),
+                ..Default::default()
+            };
+            result.total.add(&counts);
+            if sample != "Undetermined" {
+                result.add_sample(sample, &counts);
+            }
+        }
+
+        if let Some(quality_csv) = quality_csv {
+            let quality = csv_table(quality_csv);
+            for row in quality.rows.iter() {
+                let sample = quality.get(row, "SampleID").unwrap_or_default();
+                let counts = Counts {
+                    reads: 0,
+                    bases: quality.get(row, "Yield").and_then(|y| y.parse().ok()),
+                    bases_q30: quality.get(row, "YieldQ30").and_then(|y| y.parse().ok()),
+                };
+                result.total.add(&counts);
+                if sample != "Undetermined" {
+                    result.add_sample(sample, &counts);
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Counts of a DemuxResults or Undetermined entry of Stats.json
+fn json_counts(demux: &Value) -> Counts {
+    let read_metrics = demux["ReadMetrics"].as_array();
+    Counts {
+        reads: demux["NumberReads"].as_i64().unwrap_or_default(),
+        bases: demux["Yield"].as_i64(),
+        bases_q30: read_metrics.map(|metrics| metrics.iter().filter_map(|m| m["YieldQ30"].as_i64()).sum()),
+    }
+}
+
+/// Interprets a CSV file as table with the column names in the first line
+fn csv_table(csv: &str) -> Table {
+    let mut records = parse_csv(csv.trim_start_matches('\u{feff}')).into_iter();
+    Table {
+        columns: records.next().unwrap_or_default().iter().map(|c| c.trim().to_string()).collect(),
+        rows: records.collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_json() {
+        let stats = DemuxStats::from_stats_json(r#"{
+            "Flowcell": "000000000-DCWMD",
+            "ConversionResults": [{
+                "LaneNumber": 1, "TotalClustersRaw": 1200, "TotalClustersPF": 1000, "Yield": 302000,
+                "DemuxResults": [
+                    {"SampleId": "D-21-01234_IGH", "SampleName": "D-21-01234_IGH", "NumberReads": 600, "Yield": 181200,
+                     "ReadMetrics": [{"ReadNumber": 1, "Yield": 90600, "YieldQ30": 80000}, {"ReadNumber": 2, "Yield": 90600, "YieldQ30": 70000}]}
+                ],
+                "Undetermined": {"NumberReads": 400, "Yield": 120800,
+                    "ReadMetrics": [{"ReadNumber": 1, "Yield": 60400, "YieldQ30": 30000}, {"ReadNumber": 2, "Yield": 60400, "YieldQ30": 20000}]}
+            }]
+        }"#).unwrap();
+
+        assert_eq!(stats.samples["D-21-01234_IGH"], Counts { reads: 600, bases: Some(181200), bases_q30: Some(150000) });
+        assert_eq!(stats.total, Counts { reads: 1000, bases: Some(302000), bases_q30: Some(200000) });
+    }
+
+    #[test]
+    fn bclconvert() {
+        let stats = DemuxStats::from_bclconvert(
+            "Lane,SampleID,Sample_Project,Index,# Reads,# Perfect Index Reads\n\
+             1,S1,MS_ALL,ACGT-TTGG,100,90\n\
+             2,S1,MS_ALL,ACGT-TTGG,50,45\n\
+             1,Undetermined,,,10,0\n",
+            Some("Lane,SampleID,Sample_Project,Index,ReadNumber,Yield,YieldQ30,QualityScoreSum\n\
+                  1,S1,MS_ALL,ACGT-TTGG,1,15000,14000,500000\n\
+                  1,S1,MS_ALL,ACGT-TTGG,2,15000,13000,500000\n"),
+        ).unwrap();
+
+        assert_eq!(stats.samples["S1"], Counts { reads: 150, bases: Some(30000), bases_q30: Some(27000) });
+        assert_eq!(stats.total.reads, 160);
+        assert!(!stats.samples.contains_key("Undetermined"));
+    }
+}
@@ -3,14 +3,16 @@ use std::error::Error;
 use std::path::{Path, PathBuf};
 
 
+use chrono::{DateTime, Local, NaiveDate};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sql_types::Text;
 use rayon::prelude::*;
 use rocket_sync_db_pools::database;
 
 use walkdir::WalkDir;
 
+use crate::filter::{run_sample_count, Expr, Order, RunQuery, SampleQuery};
+use crate::report::{RunReport, RunStatus, UpdateReport};
 use crate::samplesheet::normalize_dna_nr;
 use crate::{models, run};
 
@@ -22,129 +24,651 @@ pub fn establish_connection(url: &str) -> PgConnection {
     PgConnection::establish(url).expect("Error connecting to database")
 }
 
-pub fn flush(conn: &PgConnection)  {
-    if let Err(e) = conn.transaction::<_, diesel::result::Error, _>(|| {
-        diesel::delete(crate::schema::fastq::table).execute(conn)?;
-        diesel::delete(crate::schema::sample::table).execute(conn)?;
-        diesel::delete(crate::schema::run::table).execute(conn)?;
-        Ok(())
-    }) {
-        error!("Could not flush db: {}", e);
+/// Outcome of synchronizing a single run with the database
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UpsertStatus {
+    /// The run was not known before and has been inserted
+    New,
+    /// The run was known, but its metadata, samples or FASTQs have changed
+    Changed,
+    /// The database already reflects the run
+    Unchanged,
+}
+
+/// Number of reads of a sample according to its counted first-read FASTQs, if all of them have been counted
+fn first_read_count(fastqs: &[models::Fastq]) -> Option<i64> {
+    let counts: Vec<Option<i64>> = fastqs
+        .iter()
+        .filter(|f| f.read.as_deref() == Some("R1"))
+        .map(|f| f.reads)
+        .collect();
+    if counts.is_empty() {
+        return None;
     }
+    counts.into_iter().sum()
 }
 
-pub fn update(conn: &PgConnection, rundir: &Path, celldir: &Path) -> Result<(), Box<dyn Error>> {
-    info!(
-        "Starting run discovery using {} threads",
-        rayon::current_num_threads()
-    );
+/// Inserts a run or brings an existing one up to date.
+///
+/// Samples that can be found in the database already keep their id. Samples
+/// are matched by their key (see `run::sample_key`). Samples that vanished
+/// from the run are deleted (along with their FASTQs).
+///
+/// The run's issues replace whatever issues were stored for it before.
+pub fn upsert_run(conn: &PgConnection, r: run::Run) -> QueryResult<UpsertStatus> {
+    use crate::schema::{fastq, run, run_issue, sample};
+
+    let new_run = r.to_schema_run();
+    let issues: Vec<models::NewRunIssue> = r.issues
+        .iter()
+        .map(|i| models::NewRunIssue { run: new_run.name.clone(), kind: i.kind.as_str().to_string(), message: i.message.clone() })
+        .collect();
+    let fastq_meta = r.fastq_meta;
+    let mut samples = r.samples;
+    for (s, _) in samples.iter_mut() {
+        s.run = new_run.name.clone();
+    }
+
+    let old_run: Option<models::Run> = run::table.find(&new_run.name).first(conn).optional()?;
+    let mut changed = match &old_run {
+        None => {
+            debug!("Add run {}", &new_run.name);
+            diesel::insert_into(run::table).values(&new_run).execute(conn)?;
+            false
+        },
+        Some(old_run) if *old_run != new_run => {
+            debug!("Update run {}", &new_run.name);
+            diesel::update(run::table.find(&new_run.name)).set(&new_run).execute(conn)?;
+            true
+        },
+        Some(_) => false,
+    };
+
+    // samples already known for this run, in order of creation
+    let mut old_samples: Vec<Option<models::Sample>> = sample::table
+        .filter(sample::run.eq(&new_run.name))
+        .order(sample::id)
+        .load::<models::Sample>(conn)?
+        .into_iter()
+        .map(Some)
+        .collect();
+
+    for (mut new_sample, mut files) in samples.into_iter() {
+        files.sort_unstable();
+
+        let old_sample = old_samples
+            .iter_mut()
+            .find(|s| s.as_ref().map(|s| s.key == new_sample.key).unwrap_or(false))
+            .and_then(|s| s.take());
+
+        let mut old_fastqs: Vec<models::Fastq> = match &old_sample {
+            Some(old_sample) => fastq::table.filter(fastq::sample_id.eq(old_sample.id)).load(conn)?,
+            None => Vec::new(),
+        };
+        old_fastqs.sort_unstable_by(|a, b| a.filename.cmp(&b.filename));
+
+        let sample_id = old_sample.as_ref().map(|s| s.id).unwrap_or_default();
+        let mut fastqs: Vec<models::Fastq> = files
+            .into_iter()
+            .map(|f| { let meta = fastq_meta.get(&f); crate::run::new_fastq(f, sample_id, meta) })
+            .collect();
+
+        // checksums and read counts are only computed on request, keep those of unchanged files
+        for f in fastqs.iter_mut() {
+            if let Some(old) = old_fastqs.iter().find(|o| o.filename == f.filename && o.size == f.size && o.mtime == f.mtime) {
+                if f.md5.is_none() && f.sha256.is_none() {
+                    f.md5 = old.md5.clone();
+                    f.sha256 = old.sha256.clone();
+                }
+                if f.reads.is_none() {
+                    f.reads = old.reads;
+                    f.mean_quality = old.mean_quality;
+                }
+            }
+        }
+
+        // without demultiplexing statistics, fall back to counting the FASTQs
+        if new_sample.reads.is_none() {
+            new_sample.reads = first_read_count(&fastqs);
+        }
+
+        if let Some(old_sample) = old_sample {
+            if models::NewSample::from_sample(&old_sample) != new_sample {
+                diesel::update(sample::table.find(old_sample.id)).set(&new_sample).execute(conn)?;
+                changed = true;
+            }
 
-    // discover entries on file system
+            if old_fastqs == fastqs {
+                continue;
+            }
+            diesel::delete(fastq::table.filter(fastq::sample_id.eq(old_sample.id))).execute(conn)?;
+            changed = true;
+        } else {
+            changed = true;
+            let sample_id = diesel::insert_into(sample::table)
+                .values(&new_sample)
+                .returning(sample::id)
+                .get_result(conn)?;
+            for f in fastqs.iter_mut() {
+                f.sample_id = sample_id;
+            }
+        }
+
+        diesel::insert_into(fastq::table).values(fastqs).execute(conn)?;
+    }
+
+    // whatever is left over has disappeared from the run
+    let vanished: Vec<i32> = old_samples.into_iter().flatten().map(|s| s.id).collect();
+    if !vanished.is_empty() {
+        debug!("{}: removing {} vanished samples", &new_run.name, vanished.len());
+        diesel::delete(sample::table.filter(sample::id.eq_any(vanished))).execute(conn)?;
+        changed = true;
+    }
+
+    diesel::delete(run_issue::table.filter(run_issue::run.eq(&new_run.name))).execute(conn)?;
+    diesel::insert_into(run_issue::table).values(&issues).execute(conn)?;
+
+    Ok(match (old_run, changed) {
+        (None, _) => UpsertStatus::New,
+        (Some(_), true) => UpsertStatus::Changed,
+        (Some(_), false) => UpsertStatus::Unchanged,
+    })
+}
+
+/// Marks all runs as removed whose path is not in `paths`. Returns the number of newly removed runs.
+fn mark_removed(conn: &PgConnection, paths: &[String]) -> QueryResult<usize> {
+    use crate::schema::run;
+
+    diesel::update(run::table
+            .filter(run::removed.is_null())
+            .filter(diesel::dsl::not(run::path.eq_any(paths))))
+        .set(run::removed.eq(diesel::dsl::now))
+        .execute(conn)
+}
+
+/// Finds run folders and zip files in `rundir`.
+///
+/// Runs are expected at depth 3 below `rundir`. If `since` is given, only entries
+/// that have been modified on or after that date are returned.
+pub fn discover(rundir: &Path, since: Option<NaiveDate>) -> Vec<String> {
     let walker = WalkDir::new(rundir).follow_links(true).max_depth(3).into_iter();
     let mut paths: Vec<String> = Vec::new();
     for entry in walker {
-        let entry = entry.unwrap();
-        if entry.depth() == 3 {
-            paths.push(entry.path().to_string_lossy().to_string());
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping unreadable entry in {}: {}", rundir.display(), e);
+                continue;
+            },
+        };
+        if entry.depth() != 3 {
+            continue;
+        }
+        if let Some(since) = since {
+            let modified = entry.metadata().ok().and_then(|m| m.modified().ok());
+            match modified {
+                Some(t) if DateTime::<Local>::from(t).naive_local().date() < since => continue,
+                Some(_) => {},
+                None => warn!("Cannot determine modification time of {}, including it anyway", entry.path().display()),
+            }
+        }
+        match normalize_run_path(entry.path()) {
+            Ok(p) => paths.push(p),
+            Err(e) => warn!("Cannot resolve {}, skipping: {}", entry.path().display(), e),
+        }
+    }
+    paths
+}
+
+/// The form in which run paths are stored: absolute and with all symlinks resolved, so that
+/// a run has the same path no matter whether it was found by `discover` or given explicitly
+pub fn normalize_run_path(path: &Path) -> std::io::Result<String> {
+    Ok(std::fs::canonicalize(path)?.to_string_lossy().to_string())
+}
+
+/// Loads size, modification time, checksums and read counts of the FASTQs of the given runs,
+/// by run name and file name
+fn known_fastqs(conn: &PgConnection, runs: &[String]) -> QueryResult<HashMap<String, HashMap<String, run::FastqMeta>>> {
+    use crate::schema::{fastq, sample};
+
+    let rows: Vec<(String, models::Fastq)> = fastq::table
+        .inner_join(sample::table)
+        .select((sample::run, fastq::all_columns))
+        .filter(sample::run.eq_any(runs))
+        .load(conn)?;
+
+    let mut known: HashMap<String, HashMap<String, run::FastqMeta>> = HashMap::new();
+    for (run, f) in rows {
+        if let Some(size) = f.size {
+            known.entry(run).or_default().insert(f.filename, run::FastqMeta {
+                size,
+                mtime: f.mtime,
+                md5: f.md5,
+                sha256: f.sha256,
+                reads: f.reads,
+                mean_quality: f.mean_quality,
+            });
         }
     }
+    Ok(known)
+}
+
+/// Parses the runs in `paths` and feeds them into the database. Paths that cannot be
+/// parsed are kept as failures until they can (see `failed_runs`).
+///
+/// If `removed_check` is set, `paths` is considered to be the complete list of runs
+/// and all runs that are not part of it are marked as removed. If `checksums` or
+/// `count_reads` is set, checksums or read counts of new or modified FASTQs are
+/// computed.
+fn sync_runs(conn: &PgConnection, paths: &[String], celldir: &Path, removed_check: bool, checksums: bool, count_reads: bool) -> Result<UpdateReport, Box<dyn Error>> {
+    let started = Local::now().naive_local();
+    info!(
+        "Parsing {} runs using {} threads",
+        paths.len(),
+        rayon::current_num_threads()
+    );
 
-    // try to make actual `Run`s of it
-    let mut runs: Vec<run::Run> = vec![];
+    // try to make actual `Run`s of it, keeping track of what went wrong
+    let mut runs: Vec<(usize, Result<run::Run, String>)> = vec![];
     runs.par_extend(
         paths
-            .into_par_iter()
-            .filter_map(|path| run::Run::from_path(&PathBuf::from(path), celldir).ok()),
+            .par_iter()
+            .enumerate()
+            .map(|(idx, path)| (idx, run::Run::from_path(&PathBuf::from(path), celldir)
+                .map_err(|e| { warn!("{}: {}", path, e); e.to_string() }))),
     );
+    runs.sort_unstable_by_key(|(idx, _)| *idx);
 
-    info!("Populating database with {} runs", runs.len());
+    if checksums || count_reads {
+        let names: Vec<String> = runs.iter().filter_map(|(_, r)| r.as_ref().ok()).map(|r| r.name.clone()).collect();
+        let known = known_fastqs(conn, &names)?;
+        let none = HashMap::new();
+        info!("Reading FASTQs for{}{}", if checksums { " checksums" } else { "" }, if count_reads { " read counts" } else { "" });
+        runs.par_iter_mut().for_each(|(idx, r)| {
+            if let Ok(run) = r {
+                let known = known.get(&run.name).unwrap_or(&none);
+                let result = if checksums { run.compute_checksums(known) } else { Ok(0) }
+                    .and_then(|n| {
+                        debug!("{}: computed checksums of {} FASTQs", run.name, n);
+                        if count_reads { run.count_reads(known) } else { Ok(0) }
+                    });
+                match result {
+                    Ok(n) => debug!("{}: counted reads of {} FASTQs", run.name, n),
+                    Err(e) => {
+                        warn!("{}: {}", paths[*idx], e);
+                        *r = Err(format!("Cannot read FASTQs: {}", e));
+                    },
+                }
+            }
+        });
+    }
+
+    info!("Synchronizing database with {} runs", runs.iter().filter(|(_, r)| r.is_ok()).count());
     // feed into database
-    conn.transaction::<_, diesel::result::Error, _>(|| {
-        for r in runs.into_iter() {
-            let new_run = r.to_schema_run();
-            let mut samples = r.samples;
-            // Run is still a bit special and needs conversion
-            
-            debug!("Add run {}", &r.name);
-            let run_query = diesel::insert_into(crate::schema::run::table)
-                .values(&new_run);
-
-            run_query
-                .execute(conn).expect("Could not insert run");
-            
-            let sample_models = samples.iter_mut().map(|(a,_)| {a.run = new_run.name.clone(); &*a}).collect::<Vec<_>>();
-
-            let sample_ids_query = diesel::insert_into(crate::schema::sample::table)
-                .values(sample_models)
-                .returning(crate::schema::sample::id);
- 
-            let sample_ids: Vec<i32> = sample_ids_query.get_results(conn)
-                .expect("Could not insert samples");
-
-            for (sample_idx, sample_id) in sample_ids.into_iter().enumerate() {
-                let fastqs: Vec<models::Fastq> = samples[sample_idx].1.iter().map(|f| models::Fastq {filename: f.to_string(), sample_id }).collect();
-                diesel::insert_into(crate::schema::fastq::table)
-                    .values(fastqs)
-                    .execute(conn)
-                    .expect("Could not insert fastqs");
+    let mut reports: Vec<RunReport> = Vec::new();
+    let removed = conn.transaction::<_, diesel::result::Error, _>(|| {
+        for (idx, r) in runs.into_iter() {
+            match r {
+                Ok(r) => {
+                    let mut report = RunReport::from_run(&paths[idx], &r);
+                    report.status = upsert_run(conn, r)?.into();
+                    clear_failure(conn, &paths[idx])?;
+                    reports.push(report);
+                },
+                Err(e) => {
+                    record_failure(conn, &paths[idx], &e)?;
+                    reports.push(RunReport::failed(&paths[idx], &e));
+                },
             }
-            
         }
-        Ok(())
+        if removed_check {
+            clear_vanished_failures(conn, paths)?;
+            mark_removed(conn, paths)
+        } else {
+            Ok(0)
+        }
     })?;
 
+    let report = UpdateReport {
+        started,
+        finished: Local::now().naive_local(),
+        runs: reports,
+        removed,
+    };
+    info!("{} new, {} changed, {} unchanged, {} failed, {} removed runs",
+        report.count(RunStatus::New),
+        report.count(RunStatus::Changed),
+        report.count(RunStatus::Unchanged),
+        report.count(RunStatus::Failed),
+        removed);
+
+    Ok(report)
+}
+
+/// Remembers that the run at `path` could not be ingested, replacing an earlier failure
+pub fn record_failure(conn: &PgConnection, path: &str, error: &str) -> QueryResult<()> {
+    use crate::schema::run_failure;
+    diesel::insert_into(run_failure::table)
+        .values((run_failure::path.eq(path), run_failure::error.eq(error)))
+        .on_conflict(run_failure::path)
+        .do_update()
+        .set((run_failure::error.eq(error), run_failure::failed.eq(diesel::dsl::now)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Forgets an earlier failure of the run at `path`, once it has been ingested
+pub fn clear_failure(conn: &PgConnection, path: &str) -> QueryResult<()> {
+    use crate::schema::run_failure;
+    diesel::delete(run_failure::table.find(path)).execute(conn)?;
     Ok(())
 }
 
-pub fn query(conn: &PgConnection, needle: &str, filters: &HashMap<String,String>, limit: Option<usize>) -> HashMap<models::Sample, Vec<String>> {
-    // get sample ids of samples where the query string matches a fastq filename
+/// Forgets the failures of all paths that are not in `paths`, since they have disappeared
+fn clear_vanished_failures(conn: &PgConnection, paths: &[String]) -> QueryResult<usize> {
+    use crate::schema::run_failure;
+    diesel::delete(run_failure::table.filter(diesel::dsl::not(run_failure::path.eq_any(paths))))
+        .execute(conn)
+}
 
-    let mut filter_sql = String::from("");
-    for f in filters.keys() {
-        match f.as_ref() {
-            "cells<" | "cells>" | "cells" | "lims_id<" | "lims_id>" | "lims_id" => {
-                filter_sql.push_str(&format!(
-                    " AND {}={}",
-                    f,
-                    filters.get(f).unwrap()
-                ));
-            },
-            "run" | "name" | "dna_nr" | "project" | "primer_set" | "filename" => {
-                 filter_sql.push_str(&format!(
-                    " AND {} ILIKE '{}'",
-                    f,
-                    filters.get(f).unwrap()
-                ));
-            },
-            _ => {
-                warn!("Ignoring unsupported filter: {}", f);
-            }
+/// Loads the run paths that could not be ingested, most recent failures first
+pub fn failed_runs(conn: &PgConnection) -> QueryResult<Vec<models::RunFailure>> {
+    use crate::schema::run_failure;
+    run_failure::table
+        .order((run_failure::failed.desc(), run_failure::path))
+        .load(conn)
+}
 
-        }
+/// Loads all runs that have issues, latest runs first
+pub fn runs_with_issues(conn: &PgConnection) -> QueryResult<Vec<(models::Run, Vec<models::RunIssue>)>> {
+    use crate::schema::{run, run_issue};
+
+    let issues: Vec<models::RunIssue> = run_issue::table.order(run_issue::id).load(conn)?;
+    let mut names: Vec<&str> = issues.iter().map(|i| i.run.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let runs: Vec<models::Run> = run::table
+        .filter(run::name.eq_any(names))
+        .filter(run::removed.is_null())
+        .order((run::date.desc(), run::name))
+        .load(conn)?;
+
+    let mut issues_by_run: HashMap<String, Vec<models::RunIssue>> = HashMap::new();
+    for i in issues {
+        issues_by_run.entry(i.run.clone()).or_default().push(i);
     }
-    
+    Ok(runs
+        .into_iter()
+        .map(|r| { let issues = issues_by_run.remove(&r.name).unwrap_or_default(); (r, issues) })
+        .collect())
+}
+
+/// A page of runs
+#[derive(Debug, Default)]
+pub struct RunPage {
+    /// Number of matching runs on all pages
+    pub total: i64,
+    /// Runs on this page together with their number of samples
+    pub runs: Vec<(models::Run, i64)>,
+}
+
+/// Runs still present on disk matching `filter` and sorted by `order`. Returns at most
+/// `limit` runs, skipping the first `offset` ones.
+pub fn runs(conn: &PgConnection, filter: Option<&Expr>, order: Order, limit: Option<usize>, offset: usize) -> Result<RunPage, Box<dyn Error>> {
+    use crate::schema::run;
+
+    let filtered = || -> Result<RunQuery<'static>, String> {
+        let mut q = run::table.filter(run::removed.is_null()).into_boxed();
+        if let Some(f) = filter {
+            q = q.filter(f.to_run_expression()?);
+        }
+        Ok(q)
+    };
+
+    let total: i64 = filtered()?.count().get_result(conn)?;
+    let mut q = order
+        .apply_to_runs(filtered()?)?
+        .select((run::all_columns, run_sample_count()))
+        .offset(offset as i64);
     if let Some(count) = limit {
-        filter_sql.push_str(&format!(" LIMIT {}", count));
+        q = q.limit(count as i64);
     }
+    let runs: Vec<(models::Run, i64)> = q.load(conn)?;
+    Ok(RunPage { total, runs })
+}
 
-    let statement =
-        format!("SELECT sample.*,fastq.* FROM sample INNER JOIN fastq ON sample.id=fastq.sample_id AND sample.id in (SELECT DISTINCT sample.id FROM sample INNER JOIN fastq ON sample.id=fastq.sample_id WHERE fastq.filename ILIKE $1 {})", filter_sql);
-    debug!("Q: {}", statement);
-    let results: Vec<(models::Sample,models::Fastq)> = diesel::sql_query(&statement)
-        .bind::<Text,_>(needle)
+/// Samples of a run with their FASTQs, in the order of the sample numbers
+pub fn run_samples(conn: &PgConnection, name: &str) -> QueryResult<Vec<(models::Sample, Vec<models::Fastq>)>> {
+    use crate::schema::{fastq, sample};
+
+    let samples: Vec<models::Sample> = sample::table
+        .filter(sample::run.eq(name))
+        .order((sample::sample_number, sample::name, sample::id))
+        .load(conn)?;
+    let ids: Vec<i32> = samples.iter().map(|s| s.id).collect();
+    let mut fastqs: HashMap<i32, Vec<models::Fastq>> = HashMap::new();
+    for f in fastq::table.filter(fastq::sample_id.eq_any(ids)).order(fastq::filename).load::<models::Fastq>(conn)? {
+        fastqs.entry(f.sample_id).or_default().push(f);
+    }
+    Ok(samples
+        .into_iter()
+        .map(|s| { let f = fastqs.remove(&s.id).unwrap_or_default(); (s, f) })
+        .collect())
+}
+
+/// The run with the given name, if known
+pub fn get_run(conn: &PgConnection, name: &str) -> QueryResult<Option<models::Run>> {
+    use crate::schema::run;
+    run::table.find(name).first(conn).optional()
+}
+
+/// The sample with the given id, if known
+pub fn get_sample(conn: &PgConnection, id: i32) -> QueryResult<Option<models::Sample>> {
+    use crate::schema::sample;
+    sample::table.find(id).first(conn).optional()
+}
+
+/// Samples with the given DNA number apart from the sample with id `except`, in all runs
+pub fn samples_with_dna_nr(conn: &PgConnection, dna_nr: &str, except: i32) -> QueryResult<Vec<models::Sample>> {
+    use crate::schema::sample;
+    sample::table
+        .filter(sample::dna_nr.eq(dna_nr))
+        .filter(sample::id.ne(except))
+        .order((sample::run, sample::name))
         .load(conn)
-        .expect("Couldn't retrieve results");
+}
 
-    let mut result: HashMap<models::Sample, Vec<String>> = HashMap::new();
-    for (sample, fastq) in results.into_iter() {
-        if let Some(v ) = result.get_mut(&sample) {
-            v.push(fastq.filename);
-        } else {
-            result.insert(sample, vec![fastq.filename]);
+/// FASTQs of the sample with the given id, or `None` if there is no such sample
+pub fn sample_fastqs(conn: &PgConnection, id: i32) -> QueryResult<Option<Vec<models::Fastq>>> {
+    use crate::schema::{fastq, sample};
+
+    let exists: bool = diesel::select(diesel::dsl::exists(sample::table.find(id))).get_result(conn)?;
+    if !exists {
+        return Ok(None);
+    }
+    fastq::table
+        .filter(fastq::sample_id.eq(id))
+        .order(fastq::filename)
+        .load(conn)
+        .map(Some)
+}
+
+/// Samples with the given keys, in the order of runs and sample numbers
+pub fn samples_by_key(conn: &PgConnection, keys: &[String]) -> QueryResult<Vec<models::Sample>> {
+    use crate::schema::sample;
+    sample::table
+        .filter(sample::key.eq_any(keys))
+        .order((sample::run, sample::sample_number, sample::name))
+        .load(conn)
+}
+
+/// Stores a new basket and returns its id
+pub fn create_basket(conn: &PgConnection, name: &str, content: &models::BasketContent) -> Result<i32, Box<dyn Error>> {
+    use crate::schema::samplesheet;
+    let basket = models::NewBasket {
+        basket: serde_json::to_string(content)?,
+        name: name.to_string(),
+    };
+    let id = diesel::insert_into(samplesheet::table)
+        .values(&basket)
+        .returning(samplesheet::id)
+        .get_result(conn)?;
+    Ok(id)
+}
+
+/// The basket with the given id, if known
+pub fn get_basket(conn: &PgConnection, id: i32) -> QueryResult<Option<models::Basket>> {
+    use crate::schema::samplesheet;
+    samplesheet::table.find(id).first(conn).optional()
+}
+
+/// All baskets, most recently modified first
+pub fn baskets(conn: &PgConnection) -> QueryResult<Vec<models::Basket>> {
+    use crate::schema::samplesheet;
+    samplesheet::table
+        .order((samplesheet::modified.desc(), samplesheet::id.desc()))
+        .load(conn)
+}
+
+/// Replaces the contents of a basket. Returns `false` if there is no such basket.
+pub fn update_basket(conn: &PgConnection, id: i32, content: &models::BasketContent) -> Result<bool, Box<dyn Error>> {
+    use crate::schema::samplesheet;
+    let updated = diesel::update(samplesheet::table.find(id))
+        .set((
+            samplesheet::basket.eq(serde_json::to_string(content)?),
+            samplesheet::modified.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    Ok(updated > 0)
+}
+
+/// Loads a basket and locks it until the end of the transaction, so that concurrent
+/// changes made in between reading and writing it back are not lost
+pub fn lock_basket(conn: &PgConnection, id: i32) -> QueryResult<Option<models::Basket>> {
+    use crate::schema::samplesheet;
+    samplesheet::table.find(id).for_update().first(conn).optional()
+}
+
+/// Adds samples to and removes samples from a basket in a single transaction.
+/// Returns the new contents, or `None` if there is no such basket.
+pub fn modify_basket(conn: &PgConnection, id: i32, add: &[String], remove: &[String]) -> Result<Option<models::BasketContent>, Box<dyn Error>> {
+    conn.transaction(|| {
+        let old = match lock_basket(conn, id)? {
+            Some(b) => b.content(),
+            None => return Ok(None),
+        };
+        let mut content = old.clone();
+        for key in add {
+            if !content.samples.contains(key) {
+                content.samples.push(key.clone());
+            }
+        }
+        content.samples.retain(|k| !remove.contains(k));
+        content.extra_cols.retain(|k, _| !remove.contains(k));
+        if content != old {
+            update_basket(conn, id, &content)?;
         }
+        Ok(Some(content))
+    })
+}
+
+/// Stores an update report in the `update_log` table
+pub fn store_report(conn: &PgConnection, report: &UpdateReport) -> Result<(), Box<dyn Error>> {
+    let entry = models::NewUpdateLog {
+        started: report.started,
+        finished: report.finished,
+        report: report.to_json()?,
+    };
+    diesel::insert_into(crate::schema::update_log::table)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Synchronizes the database with the runs found in `rundir`.
+///
+/// Without `since`, this is a full update that also marks runs as removed that
+/// have disappeared from `rundir`. With `since`, only recently modified runs are
+/// considered and all other runs are left untouched.
+pub fn update(conn: &PgConnection, rundir: &Path, celldir: &Path, since: Option<NaiveDate>, checksums: bool, count_reads: bool) -> Result<UpdateReport, Box<dyn Error>> {
+    info!("Starting run discovery in {}", rundir.display());
+    let paths = discover(rundir, since);
+
+    // an empty run folder is more likely an unmounted share than a deliberate cleanup
+    if paths.is_empty() && since.is_none() {
+        warn!("No runs found in {}, not marking any runs as removed", rundir.display());
     }
+    sync_runs(conn, &paths, celldir, since.is_none() && !paths.is_empty(), checksums, count_reads)
+}
+
+/// Adds or updates only the given run folders or zip files, leaving all other runs untouched.
+pub fn update_runs(conn: &PgConnection, runs: &[PathBuf], celldir: &Path, checksums: bool, count_reads: bool) -> Result<UpdateReport, Box<dyn Error>> {
+    // run paths are used later on for extraction, so make sure they are absolute
+    let paths = runs
+        .iter()
+        .map(|r| normalize_run_path(r)
+            .map_err(|e| Box::<dyn Error>::from(format!("{}: {}", r.display(), e))))
+        .collect::<Result<Vec<String>, _>>()?;
+    sync_runs(conn, &paths, celldir, false, checksums, count_reads)
+}
 
-    result
+/// A page of query results
+#[derive(Debug, Default)]
+pub struct QueryPage {
+    /// Number of matching samples on all pages
+    pub total: i64,
+    /// Samples on this page together with the names of their FASTQs
+    pub samples: Vec<(models::Sample, Vec<String>)>,
+}
+
+/// Samples of runs still present on disk with a FASTQ matching `needle` and `filter`,
+/// sorted by `order`. Returns at most `limit` samples, skipping the first `offset` ones.
+pub fn query(conn: &PgConnection, needle: &str, filter: Option<&Expr>, order: Order, limit: Option<usize>, offset: usize) -> Result<QueryPage, Box<dyn Error>> {
+    use crate::schema::{fastq, run, sample};
+
+    let filtered = || -> Result<SampleQuery<'static>, String> {
+        // samples of runs that have disappeared from disk cannot be extracted anymore
+        let mut q = sample::table
+            .inner_join(run::table)
+            .select(sample::all_columns)
+            .filter(run::removed.is_null())
+            .filter(sample::id.eq_any(fastq::table.select(fastq::sample_id).filter(fastq::filename.ilike(needle.to_string()))))
+            .into_boxed();
+        if let Some(f) = filter {
+            q = q.filter(f.to_expression()?);
+        }
+        Ok(q)
+    };
+
+    let total: i64 = filtered()?.count().get_result(conn)?;
+
+    let mut q = order.apply(filtered()?).offset(offset as i64);
+    if let Some(count) = limit {
+        q = q.limit(count as i64);
+    }
+    debug!("Q: {}", diesel::debug_query::<diesel::pg::Pg, _>(&q));
+    let samples: Vec<models::Sample> = q.load(conn)?;
+
+    let ids: Vec<i32> = samples.iter().map(|s| s.id).collect();
+    let fastqs: Vec<(i32, String)> = fastq::table
+        .select((fastq::sample_id, fastq::filename))
+        .filter(fastq::sample_id.eq_any(ids))
+        .load(conn)?;
+    let mut filenames: HashMap<i32, Vec<String>> = HashMap::new();
+    for (id, filename) in fastqs {
+        filenames.entry(id).or_default().push(filename);
+    }
+
+    Ok(QueryPage {
+        total,
+        samples: samples
+            .into_iter()
+            .map(|s| {
+                let f = filenames.remove(&s.id).unwrap_or_default();
+                (s, f)
+            })
+            .collect(),
+    })
 }
 
 
@@ -170,7 +694,7 @@ pub fn match_samples(db: &PgConnection, lims_id: Option<i64>, dna_nr: Option<Str
     };
     
     if candidates.is_empty() {
-        return Err(Box::from("No candidates left after LIMS filter"));
+        return Ok(MatchStatus::None(String::from("No candidates left after LIMS filter")));
     }
 
     // filter by DNA nr
@@ -217,4 +741,166 @@ pub fn match_samples(db: &PgConnection, lims_id: Option<i64>, dna_nr: Option<Str
         1 => Ok(MatchStatus::One(candidates.remove(0))),
         _ => Ok(MatchStatus::Multiple(candidates))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Tests that need a database connect to `TEST_DATABASE_URL`, which must have all
+    /// migrations applied. Nothing is committed. The tests are skipped without it.
+    pub(crate) fn test_connection() -> Option<PgConnection> {
+        match std::env::var("TEST_DATABASE_URL") {
+            Ok(url) => Some(establish_connection(&url)),
+            Err(_) => {
+                eprintln!("TEST_DATABASE_URL is not set, skipping");
+                None
+            },
+        }
+    }
+
+    /// Writes a run folder with a sample sheet and a single FASTQ of two reads
+    fn write_run(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vault-test-{}", std::process::id())).join(name);
+        std::fs::create_dir_all(dir.join("Fastq")).unwrap();
+        std::fs::write(dir.join("SampleSheet.csv"), "[Header]\nInvestigator Name,AB\n\n[Data]\nSample_ID,Sample_Name,Sample_Project\nS1,S1,P1\n").unwrap();
+        let fastq = std::fs::File::create(dir.join("Fastq/S1_S1_L001_R1_001.fastq.gz")).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(fastq, flate2::Compression::default());
+        gz.write_all(b"@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIIII\n").unwrap();
+        gz.finish().unwrap();
+        dir
+    }
+
+    fn stored_fastqs(conn: &PgConnection, run: &str) -> Vec<models::Fastq> {
+        use crate::schema::{fastq, sample};
+        fastq::table
+            .inner_join(sample::table)
+            .filter(sample::run.eq(run))
+            .select(fastq::all_columns)
+            .order(fastq::filename)
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn upsert_unchanged() {
+        let conn = match test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        let name = "211020_M70821_0001_000000000-UNCHG";
+        let dir = write_run(name);
+        conn.test_transaction::<_, Box<dyn Error>, _>(|| {
+            let report = update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), true, false).unwrap();
+            assert_eq!(report.runs[0].status, RunStatus::New);
+            let stored = stored_fastqs(&conn, name);
+            assert_eq!(stored.len(), 1);
+            assert!(stored[0].md5.is_some());
+
+            // nothing changed on disk, so nothing changes in the database either, not
+            // even the checksums that have not been computed this time
+            let report = update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), false, false).unwrap();
+            assert_eq!(report.runs[0].status, RunStatus::Unchanged);
+            assert_eq!(stored_fastqs(&conn, name), stored);
+            Ok(())
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn stored_sample_reads(conn: &PgConnection, run: &str) -> Option<i64> {
+        use crate::schema::sample;
+        sample::table.filter(sample::run.eq(run)).select(sample::reads).first(conn).unwrap()
+    }
+
+    #[test]
+    fn keep_read_counts() {
+        let conn = match test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        let name = "211020_M70821_0002_000000000-READS";
+        let dir = write_run(name);
+        conn.test_transaction::<_, Box<dyn Error>, _>(|| {
+            update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), false, true).unwrap();
+            let stored = stored_fastqs(&conn, name);
+            assert_eq!(stored[0].reads, Some(2));
+            assert_eq!(stored_sample_reads(&conn, name), Some(2));
+
+            // an update without --count-reads keeps the counts of unchanged files
+            let report = update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), false, false).unwrap();
+            assert_eq!(report.runs[0].status, RunStatus::Unchanged);
+            assert_eq!(stored_fastqs(&conn, name), stored);
+            assert_eq!(stored_sample_reads(&conn, name), Some(2));
+            Ok(())
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn runs_by_sample_count() {
+        use crate::schema::{run, sample};
+        let conn = match test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        conn.test_transaction::<_, Box<dyn Error>, _>(|| {
+            for (name, samples) in &[("211020_M70821_0004_000000000-COUNT", 1), ("211020_M70821_0005_000000000-COUNT", 2)] {
+                diesel::insert_into(run::table)
+                    .values((
+                        run::name.eq(name),
+                        run::date.eq(NaiveDate::from_ymd_opt(2021, 10, 20).unwrap()),
+                        run::assay.eq(""),
+                        run::chemistry.eq(""),
+                        run::investigator.eq(""),
+                        run::path.eq("/nonexistent"),
+                    ))
+                    .execute(&conn)?;
+                for i in 0..*samples {
+                    diesel::insert_into(sample::table)
+                        .values((
+                            sample::run.eq(name),
+                            sample::name.eq(format!("S{}", i)),
+                            sample::project.eq("P1"),
+                            sample::key.eq(format!("{}-S{}", name, i)),
+                        ))
+                        .execute(&conn)?;
+                }
+            }
+
+            let filter = crate::filter::parse("run=%-COUNT", &mut Vec::new())?;
+            let order = Order::parse("-samples")?;
+            let page = runs(&conn, filter.as_ref(), order, None, 0)?;
+            let counts: Vec<(&str, i64)> = page.runs.iter().map(|(r, n)| (r.name.as_str(), *n)).collect();
+            assert_eq!(counts, vec![("211020_M70821_0005_000000000-COUNT", 2), ("211020_M70821_0004_000000000-COUNT", 1)]);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn keep_failures() {
+        let conn = match test_connection() {
+            Some(c) => c,
+            None => return,
+        };
+        let dir = std::env::temp_dir().join(format!("vault-test-{}", std::process::id())).join("not_a_run");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = normalize_run_path(&dir).unwrap();
+        let failures = |conn: &PgConnection| -> Vec<models::RunFailure> {
+            failed_runs(conn).unwrap().into_iter().filter(|f| f.path == path).collect()
+        };
+        conn.test_transaction::<_, Box<dyn Error>, _>(|| {
+            let report = update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), false, false).unwrap();
+            assert_eq!(report.runs[0].status, RunStatus::Failed);
+            assert_eq!(failures(&conn).len(), 1);
+
+            // another attempt replaces the failure, and ingesting the path forgets it
+            update_runs(&conn, std::slice::from_ref(&dir), &std::env::temp_dir(), false, false).unwrap();
+            assert_eq!(failures(&conn)[0].error, report.runs[0].error.clone().unwrap());
+            clear_failure(&conn, &path).unwrap();
+            assert!(failures(&conn).is_empty());
+            Ok(())
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Watches the run folder for new sequencing runs and registers them as soon as
+//! they are complete.
+//!
+//! The run folder usually lives on a network share where file system events are
+//! not reliably delivered, so the folder is polled instead. A new run is only
+//! ingested once it looks complete and has not changed for a while, so runs that
+//! are still being copied are not picked up half-way.
+//!
+//! Run folders look complete once the instrument software has written one of its
+//! completion markers. Until then, only the top level of a folder is looked at, since
+//! walking through a run that is still being written is expensive on a network share.
+//! Folders that never get a marker, like runs put together by hand, are left alone
+//! and have to be registered with `update`.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use diesel::prelude::*;
+use diesel::PgConnection;
+use walkdir::WalkDir;
+
+use crate::{run, vaultdb};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Marker files written by the instrument software once a run has been written out completely
+const COMPLETION_MARKERS: [&str; 2] = ["CopyComplete.txt", "RTAComplete.txt"];
+
+/// A cheap summary of a run folder or zip file that changes while it is being written
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Fingerprint {
+    files: usize,
+    bytes: u64,
+    modified: Option<SystemTime>,
+    /// Whether the run looks complete, i.e. has a completion marker or is a zip file that can be opened
+    complete: bool,
+}
+
+impl Fingerprint {
+    fn of(path: &Path) -> Fingerprint {
+        if path.is_dir() {
+            Self::of_dir(path)
+        } else {
+            Self::of_zip(path)
+        }
+    }
+
+    fn of_dir(path: &Path) -> Fingerprint {
+        let mut fp = Fingerprint { files: 0, bytes: 0, modified: None, complete: false };
+
+        for entry in WalkDir::new(path).follow_links(true).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if entry.depth() == 1 {
+                fp.complete |= COMPLETION_MARKERS.contains(&entry.file_name().to_string_lossy().as_ref());
+            }
+
+            if let Ok(meta) = entry.metadata() {
+                fp.files += 1;
+                fp.bytes += meta.len();
+                fp.modified = fp.modified.max(meta.modified().ok());
+            }
+        }
+        fp
+    }
+
+    fn of_zip(path: &Path) -> Fingerprint {
+        let meta = std::fs::metadata(path).ok();
+        Fingerprint {
+            files: 1,
+            bytes: meta.as_ref().map(|m| m.len()).unwrap_or(0),
+            modified: meta.and_then(|m| m.modified().ok()),
+            // the central directory is written last, so partial copies cannot be opened
+            complete: std::fs::File::open(path)
+                .ok()
+                .and_then(|f| zip::ZipArchive::new(f).ok())
+                .is_some(),
+        }
+    }
+}
+
+/// What can be learned about a run folder or zip file without walking through it. Only
+/// candidates are fingerprinted, which is expensive for large run folders on a network share.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Key {
+    /// Latest modification time of the run and its completion markers
+    modified: Option<SystemTime>,
+    /// Whether the run may be complete, i.e. is a zip file or has a completion marker
+    candidate: bool,
+}
+
+impl Key {
+    fn of(path: &Path) -> Key {
+        let mut key = Key {
+            modified: std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+            candidate: !path.is_dir(),
+        };
+        for name in COMPLETION_MARKERS.iter() {
+            if let Ok(meta) = std::fs::metadata(path.join(name)) {
+                key.candidate = true;
+                key.modified = key.modified.max(meta.modified().ok());
+            }
+        }
+        key
+    }
+}
+
+/// A run that has been discovered but not been ingested yet
+struct Pending {
+    fingerprint: Fingerprint,
+    /// When the fingerprint was last seen changing
+    since: Instant,
+}
+
+/// Paths of all runs that are registered and still present
+fn known_paths(conn: &PgConnection) -> QueryResult<HashSet<String>> {
+    use crate::schema::run;
+    Ok(run::table
+        .select(run::path)
+        .filter(run::removed.is_null())
+        .load::<String>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Parses a single run and stores it in the database
+fn ingest(conn: &PgConnection, path: &Path, celldir: &Path) -> Result<()> {
+    let r = run::Run::from_path(path, celldir)?;
+    let name = r.name.clone();
+    let samples = r.samples.len();
+    let status = conn.transaction::<_, diesel::result::Error, _>(|| {
+        let status = vaultdb::upsert_run(conn, r)?;
+        vaultdb::clear_failure(conn, &path.to_string_lossy())?;
+        Ok(status)
+    })?;
+    info!("Ingested {} from {} with {} samples ({:?})", name, path.display(), samples, status);
+    Ok(())
+}
+
+/// Polls `rundir` every `interval` and ingests new runs once they are complete and
+/// have not changed for `settle`. Never returns unless the database fails.
+pub fn watch(conn: &PgConnection, rundir: &Path, celldir: &Path, interval: Duration, settle: Duration) -> Result<()> {
+    info!("Watching {} for new runs every {}s", rundir.display(), interval.as_secs());
+
+    let mut pending: HashMap<String, Pending> = HashMap::new();
+    // runs that could not be parsed are only retried once they change
+    let mut failed: HashMap<String, Key> = HashMap::new();
+
+    loop {
+        let known = known_paths(conn)?;
+        let candidates: HashSet<String> = vaultdb::discover(rundir, None)
+            .into_iter()
+            .filter(|p| !known.contains(p))
+            .collect();
+
+        // forget about runs that have vanished or have been registered by someone else
+        pending.retain(|p, _| candidates.contains(p));
+        failed.retain(|p, _| candidates.contains(p));
+
+        for path in candidates {
+            let key = Key::of(Path::new(&path));
+            if failed.get(&path) == Some(&key) || !key.candidate {
+                continue;
+            }
+            let fingerprint = Fingerprint::of(Path::new(&path));
+
+            let p = pending.entry(path.clone()).or_insert_with(|| {
+                debug!("Discovered new run {}", &path);
+                Pending { fingerprint: fingerprint.clone(), since: Instant::now() }
+            });
+            if p.fingerprint != fingerprint {
+                debug!("{} is still changing", &path);
+                p.fingerprint = fingerprint;
+                p.since = Instant::now();
+                continue;
+            }
+            if !p.fingerprint.complete || p.since.elapsed() < settle {
+                continue;
+            }
+
+            pending.remove(&path);
+            if let Err(e) = ingest(conn, Path::new(&path), celldir) {
+                warn!("Could not ingest {}: {}", &path, e);
+                vaultdb::record_failure(conn, &path, &e.to_string())?;
+                failed.insert(path, key);
+            }
+        }
+
+        std::thread::sleep(interval);
+    }
+}
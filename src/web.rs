@@ -2,21 +2,40 @@
 use rocket::form::Form;
 use rocket::fs::FileServer;
 use rocket::fs::TempFile;
+use rocket::http::ContentType;
 use rocket::http::Cookie;
+use rocket::http::Header;
 use rocket::http::CookieJar;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::State;
+use rocket::response::stream::ByteStream;
+use rocket::tokio::sync::mpsc;
 use rocket_dyn_templates::Template;
 use rocket::fs::relative;
 use rocket::form::FromForm;
 use rocket_dyn_templates::handlebars::Handlebars;
-use rocket_dyn_templates::handlebars::no_escape;
-use diesel::QueryDsl;
-use diesel::RunQueryDsl;
-use diesel::ExpressionMethods;
+use rocket_dyn_templates::handlebars::html_escape;
+use rocket_dyn_templates::handlebars::handlebars_helper;
+use diesel::Connection;
 
+use crate::filter::{Column, Expr, Order, LATEST_RUNS_FIRST};
 use crate::models::*;
 
 use crate::vaultdb::VaultDatabase;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Outcome of database work for a request, whose errors must be sent back from the pool's thread
+type DbResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Logs why a request failed and answers it with a server error
+fn internal_error(e: impl std::fmt::Display) -> Status {
+    error!("Request failed: {}", e);
+    Status::InternalServerError
+}
 
 macro_rules! context {
     ($($key:ident $(: $value:expr)?),*$(,)?) => {{
@@ -54,31 +73,99 @@ macro_rules! context {
     }};
 }
 
-fn parse_filters(filter_str: &str, warnings: &mut Vec<String>) -> HashMap<String,String> {
-    let mut filters = HashMap::new();
-    for f in filter_str.split_whitespace() {
-        let parts: Vec<&str> = f.split('=').collect();
-        match parts.len() {
-            1 => {
-                warnings.push(format!("Invalid filter <span class=\"font-monospace\">{}</span> rewritten as <span class=\"font-monospace\">filename=%{}%</span>. Please consult the syntax help.", parts[0], parts[0]));
-                filters.insert(String::from("filename"), format!("%{}%", parts[0]));
-            }
-            2 => {
-                if !["run","name","dna_nr","project","primer_set","filename","cells","cells<","cells>","lims_id","lims_id<","lims_id>"].contains(&parts[0]) {
-                    warnings.push(format!("Ignoring unknown filter column <span class=\"font-monospace\">{}</span>", parts[0]));
-                } else if parts[0] == "dna_nr" {
-                    let norm_dna_nr = parts[1].replace("D-", "");
-                    filters.insert(parts[0].to_string(), norm_dna_nr);
-                } else {
-                    filters.insert(parts[0].to_string(), parts[1].to_string());
-                }
-            }
-            _ => {
-                warnings.push(String::from("Invalid filter string. Only zero or more <span class=\"font-monospace\">key=value</span> pairs are allowed. Please consult the syntax help."));
-            }
-        };
+fn parse_filters(filter_str: &str, warnings: &mut Vec<String>) -> Result<Option<Expr>, ()> {
+    let mut bare_terms = Vec::new();
+    let parsed = crate::filter::parse(filter_str, &mut bare_terms);
+    for t in bare_terms {
+        let escaped = html_escape(&t);
+        warnings.push(format!("Filter <span class=\"font-monospace\">{}</span> without column interpreted as <span class=\"font-monospace\">filename=%{}%</span>. Please consult the syntax help.", escaped, escaped));
+    }
+    parsed.map_err(|e| {
+        warnings.push(format!("Invalid filter: {} at position {}: <span class=\"font-monospace\">{}</span>. Please consult the syntax help.",
+            html_escape(&e.message), e.position, html_escape(filter_str)));
+    })
+}
+
+/// Position of the shown samples within all query results
+#[derive(Serialize, Debug, Default)]
+struct Pagination {
+    /// Number of matching samples on all pages
+    total: i64,
+    page: usize,
+    pages: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+    /// Positions of the first and last shown sample, starting at 1
+    first: usize,
+    last: usize,
+}
+
+/// Number of results on the pages before `page`. Clamped to what the database accepts,
+/// so that absurd page numbers do not overflow.
+fn page_offset(limit: Option<usize>, page: usize) -> usize {
+    limit.map_or(0, |l| page.saturating_sub(1).saturating_mul(l).min(i64::MAX as usize))
+}
+
+impl Pagination {
+    fn new(total: i64, count: usize, limit: Option<usize>, page: usize) -> Self {
+        let samples = total.max(0) as usize;
+        let pages = match limit {
+            Some(l) if l > 0 => samples.saturating_sub(1) / l + 1,
+            _ => 1,
+        }.max(1);
+        let offset = page_offset(limit, page);
+        Pagination {
+            total,
+            page,
+            pages,
+            prev: if page > 1 { Some((page - 1).min(pages)) } else { None },
+            next: if page < pages { Some(page + 1) } else { None },
+            first: if count > 0 { offset.saturating_add(1) } else { 0 },
+            last: offset.saturating_add(count),
+        }
+    }
+}
+
+/// Runs a sample query for the web interface. Invalid filters or sort orders and
+/// database errors are reported as warnings.
+async fn search(conn: &VaultDatabase, filter_str: Option<&str>, sort: Option<&str>, limit: Option<usize>, page: usize, warnings: &mut Vec<String>) -> (Vec<Sample>, Pagination, Order) {
+    let order = match sort.filter(|s| !s.is_empty()).map(Order::parse) {
+        Some(Ok(order)) if order.column == Column::Samples => {
+            warnings.push(String::from("Only runs can be sorted by samples"));
+            Order::default()
+        },
+        Some(Ok(order)) => order,
+        Some(Err(e)) => {
+            warnings.push(html_escape(&e));
+            Order::default()
+        },
+        None => Order::default(),
+    };
+
+    let filter = match filter_str.map(|f| parse_filters(f, warnings)) {
+        // an invalid filter must not widen the query, so only show the warning
+        Some(Err(())) => return (Vec::new(), Pagination::default(), order),
+        Some(Ok(filter)) => filter,
+        None if limit.is_some() => None,
+        None => return (Vec::new(), Pagination::default(), order),
+    };
+
+    let offset = page_offset(limit, page);
+    let result = conn.run(move |c| {
+        crate::vaultdb::query(c, "%.fastq.gz", filter.as_ref(), order, limit, offset)
+            .map_err(|e| e.to_string())
+    }).await;
+    match result {
+        Ok(result) => {
+            let samples: Vec<Sample> = result.samples.into_iter().map(|(s, _)| s).collect();
+            let pagination = Pagination::new(result.total, samples.len(), limit, page);
+            (samples, pagination, order)
+        },
+        Err(e) => {
+            warnings.push(format!("Query failed: {}", html_escape(&e)));
+            (Vec::new(), Pagination::default(), order)
+        },
     }
-    filters
 }
 
 #[derive(FromForm, Debug)]
@@ -88,8 +175,18 @@ struct QueryResult<'a> {
 
     limit: Option<usize>,
 
+    /// Current sort order, a column name prefixed with `-` for descending order
+    sort: Option<&'a str>,
+
+    /// New sort order chosen by clicking a column header
+    resort: Option<&'a str>,
+
+    /// Page of the results, starting at 1
+    page: Option<usize>,
+
+    /// Sample keys of checked samples
     #[field(name="sample")]
-    selected_samples: Option<HashMap<&'a str, bool>>,
+    selected_samples: Vec<&'a str>,
 
     #[field(name="import_ssheet")]
     samplesheet: Option<TempFile<'a>>,
@@ -102,78 +199,576 @@ struct QueryResult<'a> {
 }
 
 
-#[route(POST, uri = "/checkout", data = "<cart>")]
-async fn checkout(conn: VaultDatabase, cart: Form<QueryResult<'_>>, cookies: &CookieJar<'_>) -> Template {
+/// Sample keys selected on the query and run pages, but not yet put into a basket
+fn cookie_selection(cookies: &CookieJar<'_>) -> Vec<String> {
+    cookies
+        .get("selected_samples")
+        .map(|c| c.value().split(',').filter(|k| !k.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Id of the basket last opened in this browser
+fn cookie_basket_id(cookies: &CookieJar<'_>) -> Option<i32> {
+    cookies.get("basket_id")?.value().parse().ok()
+}
 
-    let mut selected_samples: Vec<i32> = Vec::new();
-    if let Some(ss) = &cart.selected_samples {
-        selected_samples = ss.keys().filter_map(|k| k.parse::<i32>().ok()).collect();
+/// The basket last opened in this browser, if it still exists
+async fn current_basket(conn: &VaultDatabase, cookies: &CookieJar<'_>) -> Result<Option<Basket>, Status> {
+    let id = match cookie_basket_id(cookies) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    conn.run(move |c| crate::vaultdb::get_basket(c, id)).await.map_err(internal_error)
+}
+
+/// Sample keys selected in this browser, whether they are still in the cookie or
+/// already in the basket opened last
+async fn browser_selection(conn: &VaultDatabase, cookies: &CookieJar<'_>) -> Result<Vec<String>, Status> {
+    let mut selected = cookie_selection(cookies);
+    if let Some(basket) = current_basket(conn, cookies).await? {
+        selected.extend(basket.content().samples);
     }
-    if let Some(c) = cookies.get("selected_samples") {
-        selected_samples.append(&mut c.value().split(',').filter_map(|k| k.parse::<i32>().ok()).collect::<Vec<i32>>());
+    Ok(selected)
+}
+
+/// A row of an uploaded sample sheet as shown at checkout
+#[derive(Serialize)]
+struct ImportRow {
+    /// Row number in the sheet
+    row: usize,
+    run: String,
+    name: String,
+    /// Why the row could not be matched
+    reason: String,
+    /// The matching sample or all candidates
+    samples: Vec<Sample>,
+}
+
+/// Outcome of matching an uploaded sample sheet against the database and the basket
+#[derive(Serialize, Default)]
+struct ImportSummary {
+    matched: Vec<ImportRow>,
+    unmatched: Vec<ImportRow>,
+    ambiguous: Vec<ImportRow>,
+}
+
+/// Matches the rows of an uploaded sample sheet and adds the matched samples with their
+/// columns to `content`. Rows matching several samples are resolved if exactly one of
+/// them is already in the basket.
+fn import_samplesheet(c: &diesel::PgConnection, xlsx: &Path, content: &mut BasketContent) -> Result<ImportSummary, String> {
+    use crate::vaultdb::MatchStatus;
+
+    let workbook: calamine::Xlsx<_> = calamine::open_workbook(xlsx).map_err(|e| format!("Cannot open sample sheet: {}", e))?;
+    let rows = crate::samplesheet::import_xlsx(workbook, c).map_err(|e| format!("Cannot import sample sheet: {}", e))?;
+
+    let mut summary = ImportSummary::default();
+    for imported in rows {
+        let mut row = ImportRow {
+            row: imported.row,
+            run: imported.cols.get("run").cloned().unwrap_or_default(),
+            name: imported.cols.get("Sample").cloned().unwrap_or_default(),
+            reason: String::new(),
+            samples: Vec::new(),
+        };
+        let status = match imported.status {
+            MatchStatus::Multiple(candidates) => {
+                let (in_basket, others): (Vec<Sample>, Vec<Sample>) = candidates.into_iter().partition(|s| content.samples.contains(&s.key));
+                match in_basket.len() {
+                    1 => MatchStatus::One(in_basket.into_iter().next().unwrap()),
+                    0 => MatchStatus::Multiple(others),
+                    _ => MatchStatus::Multiple(in_basket),
+                }
+            },
+            status => status,
+        };
+        match status {
+            MatchStatus::One(sample) => {
+                if !content.samples.contains(&sample.key) {
+                    content.samples.push(sample.key.clone());
+                }
+                content.extra_cols.insert(sample.key.clone(), imported.cols);
+                row.samples.push(sample);
+                summary.matched.push(row);
+            },
+            MatchStatus::Multiple(candidates) => {
+                row.samples = candidates;
+                summary.ambiguous.push(row);
+            },
+            MatchStatus::None(reason) => {
+                row.reason = reason;
+                summary.unmatched.push(row);
+            },
+        }
     }
-    selected_samples.sort_unstable();
-    selected_samples.dedup();
+    Ok(summary)
+}
 
-    // add any samples that have been received via FormRequest to the cookie
-    let cookie_val = selected_samples.iter().map(|i| i.to_string()).collect::<Vec<String>>().join(",");
-    cookies.add(Cookie::new("selected_samples", cookie_val));
+/// Parses the comma-separated list of override columns. Only basic sample sheet columns
+/// can be overridden, all other imported columns are exported as they are.
+fn parse_overrides(cols: &str, warnings: &mut Vec<String>) -> Vec<String> {
+    let mut overrides = Vec::new();
+    for col in cols.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if crate::samplesheet::BASIC_HEADER.contains(&col) {
+            overrides.push(col.to_string());
+        } else {
+            warnings.push(format!("Column <span class=\"font-monospace\">{}</span> is not a basic sample sheet column and cannot be overridden. Valid columns are: {}",
+                html_escape(col), crate::samplesheet::BASIC_HEADER.join(", ")));
+        }
+    }
+    overrides
+}
 
+#[route(POST, uri = "/checkout", data = "<cart>")]
+async fn checkout(conn: VaultDatabase, cart: Form<QueryResult<'_>>, cookies: &CookieJar<'_>) -> Result<Template, Status> {
     debug!("Cart: {:?}", &cart);
+    let mut warnings: Vec<String> = Vec::new();
 
-    use crate::schema::sample;
-    let samples: Vec<Sample> = conn.run(|c| sample::table.filter(sample::id.eq_any(selected_samples)).load(c).expect("Error loading samples")).await;
-    //let mut samples = samples.into_iter().map(|ss| ss.to_model()).collect::<Vec<crate::sample::Sample>>();
-    
-    let _cols = cart.samplesheet_cols.unwrap_or_default();
-    // load samplesheet, if we have one
-    let samplesheet_id = 0;
-    // let samplesheet_id = if let Some(ss) = &mut cart.samplesheet {
-    //     let cols: Vec<&str> = cols.split(",").collect();
-    //     //load_samplesheet(ss, samples.as_slice(), cols.as_slice())
-    // } else if let Some(i) = cart.samplesheet_id {
-    //     i
-    // } else {
-    //     0
-    // };
-
-    //update_samples(&mut conn, samplesheet_id, &mut samples);
-
-    Template::render("checkout", context!{
+    // resume the posted basket, or else the one opened last in this browser. Samples
+    // selected meanwhile go into it, like with `basket_add`.
+    let stored_id = cart.samplesheet_id.filter(|&id| id > 0).or_else(|| cookie_basket_id(cookies));
+    let mut add = cookie_selection(cookies);
+    add.extend(cart.selected_samples.iter().map(|k| k.to_string()));
+
+    // an empty file field is sent if no sample sheet has been chosen
+    let upload = cart.samplesheet.as_ref().filter(|f| f.len() > 0);
+    let xlsx = upload.and_then(|f| f.path()).map(Path::to_path_buf);
+    if upload.is_some() && xlsx.is_none() {
+        warnings.push(String::from("The uploaded sample sheet could not be read"));
+    }
+    let overrides = cart.samplesheet_cols.map(|cols| parse_overrides(cols, &mut warnings));
+
+    // an import is kept in a new basket if there is none yet, so that it survives further updates
+    let new_name = xlsx.as_ref().map(|_| format!("Import of {}", upload.and_then(|f| f.name()).unwrap_or("sample sheet")));
+
+    let (basket, import, samples, content) = conn.run(move |c| -> DbResult<_> {
+        // the basket is locked while merging, so that concurrent changes are not lost
+        c.transaction::<_, Box<dyn std::error::Error + Send + Sync>, _>(|| {
+            let stored = match stored_id {
+                Some(id) => crate::vaultdb::lock_basket(c, id)?,
+                None => None,
+            };
+            let mut content = stored.as_ref().map(Basket::content).unwrap_or_default();
+            for key in add {
+                if !content.samples.contains(&key) {
+                    content.samples.push(key);
+                }
+            }
+            if let Some(overrides) = overrides {
+                content.overrides = overrides;
+            }
+            let import = xlsx.map(|xlsx| import_samplesheet(c, &xlsx, &mut content));
+
+            let id = match (&stored, new_name) {
+                (Some(b), _) => {
+                    if b.content() != content {
+                        crate::vaultdb::update_basket(c, b.id, &content).map_err(|e| e.to_string())?;
+                    }
+                    Some(b.id)
+                },
+                (None, Some(name)) => Some(crate::vaultdb::create_basket(c, &name, &content).map_err(|e| e.to_string())?),
+                (None, None) => None,
+            };
+            let basket = match id {
+                Some(id) => crate::vaultdb::get_basket(c, id)?,
+                None => None,
+            };
+            let samples = crate::vaultdb::samples_by_key(c, &content.samples)?;
+            Ok((basket, import, samples, content))
+        })
+    }).await.map_err(internal_error)?;
+
+    let import = match import {
+        Some(Ok(import)) => Some(import),
+        Some(Err(e)) => {
+            // warnings are shown as HTML
+            warnings.push(html_escape(&e));
+            None
+        },
+        None => None,
+    };
+
+    match &basket {
+        Some(b) => {
+            // the selection now lives in the basket
+            cookies.remove(Cookie::named("selected_samples"));
+            cookies.add(Cookie::new("basket_id", b.id.to_string()));
+        },
+        None => {
+            // add any samples that have been received via FormRequest to the cookie
+            let mut selected_samples = content.samples.clone();
+            selected_samples.sort_unstable();
+            cookies.add(Cookie::new("selected_samples", selected_samples.join(",")));
+        },
+    }
+
+    let samplesheet = crate::samplesheet::SampleSheet::with_extra_cols(samples, &content.extra_cols);
+    let header = samplesheet.header();
+    let overridden: Vec<bool> = header.iter().map(|h| content.overrides.contains(h)).collect();
+    let rows = samplesheet.rows(&content.overrides);
+    let count = rows.len();
+    let samplesheet_id = basket.as_ref().map_or(0, |b| b.id);
+
+    Ok(Template::render("checkout", context!{
+        header,
+        overridden,
+        rows,
+        count,
+        import,
+        overrides: content.overrides.join(","),
+        warnings,
+        samplesheet_id,
+        basket,
+    }))
+}
+
+/// A file offered for download under a given name
+#[derive(Responder)]
+struct Download<R> {
+    inner: (ContentType, R),
+    disposition: Header<'static>,
+}
+
+impl<R> Download<R> {
+    fn new(content_type: ContentType, filename: &str, content: R) -> Self {
+        Download {
+            inner: (content_type, content),
+            disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", filename)),
+        }
+    }
+}
+
+/// File name for a sample sheet of a basket: its name and the current date, restricted
+/// to characters that are safe in file names on all systems
+fn samplesheet_filename(basket: Option<&Basket>, extension: &str) -> String {
+    let name: String = basket
+        .map_or("samplesheet", |b| b.name.as_str())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    format!("vault_{}_{}.{}", name.trim_matches(|c| c == '_' || c == '.'), chrono::Local::now().format("%Y-%m-%d"), extension)
+}
+
+/// Sample sheet of a basket, or of the current selection if no basket is given, as shown at checkout.
+/// `format` is one of `csv`, `tsv` or `xlsx`.
+#[get("/samplesheet/<format>?<basket>")]
+async fn download_samplesheet(conn: VaultDatabase, format: &str, basket: Option<i32>, cookies: &CookieJar<'_>) -> Result<Download<Vec<u8>>, Status> {
+    if !["csv", "tsv", "xlsx"].contains(&format) {
+        return Err(Status::NotFound);
+    }
+    let selection = cookie_selection(cookies);
+    let (basket, content, samples) = conn.run(move |c| -> DbResult<_> {
+        let (basket, content) = match basket {
+            Some(id) => match crate::vaultdb::get_basket(c, id)? {
+                Some(basket) => {
+                    let content = basket.content();
+                    (Some(basket), content)
+                },
+                None => return Ok(None),
+            },
+            None => (None, BasketContent { samples: selection, ..Default::default() }),
+        };
+        let samples = crate::vaultdb::samples_by_key(c, &content.samples)?;
+        Ok(Some((basket, content, samples)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+
+    let samplesheet = crate::samplesheet::SampleSheet::with_extra_cols(samples, &content.extra_cols);
+    let filename = samplesheet_filename(basket.as_ref(), format);
+    match format {
+        "csv" => Ok(Download::new(ContentType::CSV, &filename, samplesheet.to_csv(",", &content.overrides).into_bytes())),
+        "tsv" => Ok(Download::new(ContentType::new("text", "tab-separated-values"), &filename, samplesheet.to_csv("\t", &content.overrides).into_bytes())),
+        _ => match samplesheet.to_xlsx(&content.overrides) {
+            Ok(xlsx) => Ok(Download::new(ContentType::new("application", "vnd.openxmlformats-officedocument.spreadsheetml.sheet"), &filename, xlsx)),
+            Err(e) => {
+                error!("Cannot write sample sheet: {}", e);
+                Err(Status::InternalServerError)
+            },
+        },
+    }
+}
+
+#[derive(FromForm, Debug)]
+struct BasketForm<'a> {
+    /// Name of a new basket
+    name: Option<&'a str>,
+
+    /// Sample keys to add or remove
+    #[field(name="sample")]
+    samples: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct BasketSummary {
+    basket: Basket,
+    samples: usize,
+}
+
+#[get("/baskets")]
+async fn baskets(conn: VaultDatabase, cookies: &CookieJar<'_>) -> Result<Template, Status> {
+    let baskets: Vec<BasketSummary> = conn.run(|c| crate::vaultdb::baskets(c)).await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|basket| { let samples = basket.content().samples.len(); BasketSummary { basket, samples } })
+        .collect();
+    let count = baskets.len();
+    let current = current_basket(&conn, cookies).await?.map(|b| b.id);
+
+    Ok(Template::render("baskets", context!{
+        baskets,
+        count,
+        current,
+    }))
+}
+
+/// Creates a basket from the samples selected on the query page and opens it
+#[post("/baskets", data = "<form>")]
+async fn create_basket(conn: VaultDatabase, form: Form<BasketForm<'_>>, cookies: &CookieJar<'_>) -> Result<Redirect, Status> {
+    let mut samples = cookie_selection(cookies);
+    samples.extend(form.samples.iter().map(|k| k.to_string()));
+    samples.sort_unstable();
+    samples.dedup();
+
+    let name = match form.name.map(str::trim).filter(|n| !n.is_empty()) {
+        Some(n) => n.to_string(),
+        None => format!("Basket of {}", chrono::Local::now().format("%Y-%m-%d %H:%M")),
+    };
+    let content = BasketContent { samples, ..Default::default() };
+    let id = conn.run(move |c| crate::vaultdb::create_basket(c, &name, &content).map_err(|e| e.to_string())).await
+        .map_err(internal_error)?;
+
+    // the selection now lives in the basket
+    cookies.remove(Cookie::named("selected_samples"));
+    cookies.add(Cookie::new("basket_id", id.to_string()));
+    Ok(Redirect::to(uri!(basket(id))))
+}
+
+/// Directories that extraction jobs may create folders in
+struct ExtractDirs(Vec<PathBuf>);
+
+async fn render_basket(conn: &VaultDatabase, id: i32, dirs: &ExtractDirs, warnings: Vec<String>) -> Result<Template, Status> {
+    let (basket, samples) = conn.run(move |c| -> DbResult<_> {
+        let basket = match crate::vaultdb::get_basket(c, id)? {
+            Some(basket) => basket,
+            None => return Ok(None),
+        };
+        let samples = crate::vaultdb::samples_by_key(c, &basket.content().samples)?;
+        Ok(Some((basket, samples)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+    let missing = basket.content().samples.len().saturating_sub(samples.len());
+    let count = samples.len();
+    let extract_dirs: Vec<String> = dirs.0.iter().map(|d| d.display().to_string()).collect();
+
+    Ok(Template::render("basket", context!{
+        basket,
         samples,
-        samplesheet_id
-    })
+        count,
+        missing,
+        extract_dirs,
+        warnings,
+    }))
+}
+
+/// A basket, which can be shared by its URL
+#[get("/basket/<id>")]
+async fn basket(conn: VaultDatabase, id: i32, dirs: &State<ExtractDirs>, cookies: &CookieJar<'_>) -> Result<Template, Status> {
+    let page = render_basket(&conn, id, dirs, Vec::new()).await?;
+
+    // samples added on the query page go to the basket opened last
+    cookies.add(Cookie::new("basket_id", id.to_string()));
+    Ok(page)
+}
+
+#[derive(FromForm, Debug)]
+struct ExtractForm<'a> {
+    /// One of the permitted target directories
+    dir: &'a str,
+
+    /// Folder to create in `dir`
+    folder: &'a str,
+}
+
+/// Queues an extraction of a basket's FASTQs into a new folder of a permitted directory
+#[post("/basket/<id>/extract", data = "<form>")]
+async fn basket_extract(conn: VaultDatabase, id: i32, form: Form<ExtractForm<'_>>, dirs: &State<ExtractDirs>) -> Result<Result<Redirect, Template>, Status> {
+    let target = match crate::jobs::target_dir(&dirs.0, Path::new(form.dir), form.folder.trim()) {
+        Ok(target) => target,
+        Err(e) => return render_basket(&conn, id, dirs, vec![html_escape(&e)]).await.map(Err),
+    };
+    let taken = format!("{} is already the target of another extraction", target.display());
+    let job = conn.run(move |c| -> DbResult<_> {
+        let basket = match crate::vaultdb::get_basket(c, id)? {
+            Some(basket) => basket,
+            None => return Ok(None),
+        };
+        Ok(Some(crate::jobs::submit(c, id, &basket.content(), &target)?))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+    match job {
+        Some(job) => Ok(Ok(Redirect::to(uri!(job(job))))),
+        None => render_basket(&conn, id, dirs, vec![html_escape(&taken)]).await.map(Err),
+    }
+}
+
+#[derive(Serialize)]
+struct JobSummary {
+    job: ExtractJob,
+    basket: String,
+    /// Number of samples the job was submitted with
+    samples: usize,
+    progress: crate::jobs::Progress,
+}
+
+#[get("/jobs")]
+async fn jobs(conn: VaultDatabase) -> Result<Template, Status> {
+    let jobs: Vec<JobSummary> = conn.run(|c| -> DbResult<_> {
+        let baskets: HashMap<i32, String> = crate::vaultdb::baskets(c)?
+            .into_iter()
+            .map(|b| (b.id, b.name))
+            .collect();
+        Ok(crate::jobs::jobs(c, 100)?
+            .into_iter()
+            .map(|(job, progress)| {
+                let basket = baskets.get(&job.basket_id).cloned().unwrap_or_default();
+                let samples = job.content().samples.len();
+                JobSummary { job, basket, samples, progress }
+            })
+            .collect())
+    }).await.map_err(internal_error)?;
+    let count = jobs.len();
+
+    Ok(Template::render("jobs", context!{
+        jobs,
+        count,
+    }))
+}
+
+#[get("/job/<id>")]
+async fn job(conn: VaultDatabase, id: i32) -> Result<Template, Status> {
+    let (job, files, basket, samples) = conn.run(move |c| -> DbResult<_> {
+        let (job, files) = match crate::jobs::job(c, id)? {
+            Some(job) => job,
+            None => return Ok(None),
+        };
+        let basket = crate::vaultdb::get_basket(c, job.basket_id)?;
+        // what was requested, the basket may have changed since
+        let samples = crate::vaultdb::samples_by_key(c, &job.content().samples)?;
+        Ok(Some((job, files, basket, samples)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+    let done = files.iter().filter(|f| f.status == crate::jobs::FileStatus::Done.as_str()).count();
+    let failed = files.iter().filter(|f| f.status == crate::jobs::FileStatus::Failed.as_str()).count();
+    let total = files.len();
+    let active = job.status == crate::jobs::JobStatus::Queued.as_str() || job.status == crate::jobs::JobStatus::Running.as_str();
+    let requested = job.content().samples.len();
+
+    Ok(Template::render("job", context!{
+        job,
+        files,
+        basket,
+        samples,
+        requested,
+        done,
+        failed,
+        total,
+        active,
+    }))
+}
+
+/// Size of the chunks in which a zip archive is handed over to the response
+const ZIP_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Passes everything written to it in chunks to the receiving end of a channel, which
+/// applies back pressure once a few chunks are waiting
+struct ChannelWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= ZIP_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(ZIP_CHUNK_SIZE));
+            // fails if the client has gone away
+            self.tx.blocking_send(chunk).map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Download aborted"))?;
+        }
+        Ok(())
+    }
+}
+
+/// All FASTQs of a basket with their checksums and the sample sheet as a zip archive. The
+/// archive is written while it is sent, so a failure shows as an incomplete download.
+#[get("/basket/<id>/fastqs.zip")]
+async fn basket_zip(conn: VaultDatabase, id: i32) -> Result<Download<ByteStream![Vec<u8>]>, Status> {
+    let (basket, samplesheet, sources, overrides) = conn.run(move |c| -> DbResult<_> {
+        let basket = match crate::vaultdb::get_basket(c, id)? {
+            Some(basket) => basket,
+            None => return Ok(None),
+        };
+        let content = basket.content();
+        let samples = crate::vaultdb::samples_by_key(c, &content.samples)?;
+        let samplesheet = crate::samplesheet::SampleSheet::with_extra_cols(samples, &content.extra_cols);
+        let sources = samplesheet.fastq_sources(c).map_err(|e| e.to_string())?;
+        Ok(Some((basket, samplesheet, sources, content.overrides)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(4);
+    let name = basket.name.clone();
+    rocket::tokio::task::spawn_blocking(move || {
+        let out = ChannelWriter { tx, buf: Vec::with_capacity(ZIP_CHUNK_SIZE) };
+        if let Err(e) = samplesheet.write_zip(&sources, &overrides, out) {
+            error!("Cannot write FASTQs of basket {}: {}", name, e);
+        }
+    });
+    let stream = ByteStream! {
+        while let Some(chunk) = rx.recv().await {
+            yield chunk;
+        }
+    };
+
+    Ok(Download::new(ContentType::ZIP, &samplesheet_filename(Some(&basket), "zip"), stream))
+}
+
+#[post("/basket/<id>/add", data = "<form>")]
+async fn basket_add(conn: VaultDatabase, id: i32, form: Form<BasketForm<'_>>, cookies: &CookieJar<'_>) -> Result<Redirect, Status> {
+    let mut add = cookie_selection(cookies);
+    add.extend(form.samples.iter().map(|k| k.to_string()));
+    conn.run(move |c| crate::vaultdb::modify_basket(c, id, &add, &[]).map_err(|e| e.to_string())).await
+        .map_err(internal_error)?
+        .ok_or(Status::NotFound)?;
+
+    cookies.remove(Cookie::named("selected_samples"));
+    Ok(Redirect::to(uri!(basket(id))))
+}
+
+#[post("/basket/<id>/remove", data = "<form>")]
+async fn basket_remove(conn: VaultDatabase, id: i32, form: Form<BasketForm<'_>>) -> Result<Redirect, Status> {
+    let remove: Vec<String> = form.samples.iter().map(|k| k.to_string()).collect();
+    conn.run(move |c| crate::vaultdb::modify_basket(c, id, &[], &remove).map_err(|e| e.to_string())).await
+        .map_err(internal_error)?
+        .ok_or(Status::NotFound)?;
+    Ok(Redirect::to(uri!(basket(id))))
 }
 
 #[post("/", data = "<query>")]
-async fn run_query(conn: VaultDatabase, cookies: &CookieJar<'_>, query: Form<QueryResult<'_>>) -> Template {
-    let mut filters: HashMap<String, String> = HashMap::new();
+async fn run_query(conn: VaultDatabase, cookies: &CookieJar<'_>, query: Form<QueryResult<'_>>) -> Result<Template, Status> {
     let mut warnings: Vec<String> = Vec::new();
     let query = query.into_inner();
 
     debug!("POST /: query {:?}", &query);
 
-    if let Some(filter_str) = query.filters.as_ref() {
-        filters = parse_filters(filter_str, &mut warnings);
-    }
-
-    let mut samples: Vec<Sample> = if query.filters.is_some() || query.limit.is_some() {
-        let limit = query.limit;
-        conn.run(move |c| {
-            crate::vaultdb::query(c, "%.fastq.gz", &filters, limit).into_keys().collect::<Vec<Sample>>()
-        }).await
-    } else {
-        Vec::new()
-    };
+    let page = query.page.unwrap_or(1).max(1);
+    let (samples, pagination, order) = search(&conn, query.filters, query.resort.or(query.sort), query.limit, page, &mut warnings).await;
 
-    let mut selected_samples: Vec<&str> = Vec::new();
-    if let Some(ss) = query.selected_samples {
-        selected_samples.append(&mut ss.into_keys().collect::<Vec<&str>>());
-    }
+    let mut selected_samples: Vec<&str> = query.selected_samples;
 
     // If there is a cookie, also pull the selected samples from there.
     if let Some(ss) = cookies.get("selected_samples") {
-        selected_samples.append(&mut ss.value().split(',').collect::<Vec<&str>>());
+        selected_samples.append(&mut ss.value().split(',').filter(|k| !k.is_empty()).collect::<Vec<&str>>());
     }
     selected_samples.sort_unstable();
     selected_samples.dedup();
@@ -182,66 +777,280 @@ async fn run_query(conn: VaultDatabase, cookies: &CookieJar<'_>, query: Form<Que
     let cookie_val = selected_samples.join(",");
     cookies.add(Cookie::new("selected_samples", cookie_val));
     
-    samples.sort_unstable();
     let count = samples.len();
-    let selected_samples = samples.iter().map(|s| if selected_samples.contains(&s.id.to_string().as_ref()) { 1 } else { 0 } ).collect::<Vec<u8>>();
+    let selected_samples = samples.iter().map(|s| if selected_samples.contains(&s.key.as_str()) { 1 } else { 0 } ).collect::<Vec<u8>>();
+    let basket = current_basket(&conn, cookies).await?;
     
-    Template::render("query", context!{
+    Ok(Template::render("query", context!{
         filters: query.filters, 
         limit: query.limit,
+        sort: order.to_string(),
         warnings,
         samples,
         count,
+        pagination,
         selected_samples,
-    })
+        basket,
+    }))
 }
 
-#[get("/?<filter>&<limit>")]
-async fn run_query_default(conn: VaultDatabase, filter: Option<String>, limit: Option<usize>, cookies: &CookieJar<'_>) -> Template {
+#[get("/?<filter>&<limit>&<sort>&<page>")]
+async fn run_query_default(conn: VaultDatabase, filter: Option<String>, limit: Option<usize>, sort: Option<String>, page: Option<usize>, cookies: &CookieJar<'_>) -> Result<Template, Status> {
     
-    let mut filters: HashMap<String, String> = HashMap::new();
     let mut warnings: Vec<String> = Vec::new();
 
-    if let Some(filter_str) = filter.as_ref() {
-        filters = parse_filters(filter_str, &mut warnings);
-    }
-
-    let mut samples: Vec<Sample> = if filter.is_some() || limit.is_some() {
-        conn.run(move |c| {
-            crate::vaultdb::query(c, "%.fastq.gz", &filters, limit).into_keys().collect::<Vec<Sample>>()
-        }).await
-    } else {
-        Vec::new()
-    };
-    
-    samples.sort_unstable();
+    let page = page.unwrap_or(1).max(1);
+    let (samples, pagination, order) = search(&conn, filter.as_deref(), sort.as_deref(), limit, page, &mut warnings).await;
     let count = samples.len();
 
     cookies.remove(Cookie::named("selected_samples"));
+    let basket = current_basket(&conn, cookies).await?;
 
-    Template::render("query", context!{
+    Ok(Template::render("query", context!{
         filters: filter, 
         limit,
+        sort: order.to_string(),
         warnings,
         samples,
         count,
-        selected_samples: Vec::<u8>::new()
+        pagination,
+        selected_samples: Vec::<u8>::new(),
+        basket,
+    }))
+}
+
+#[derive(Serialize)]
+struct RunSummary {
+    run: Run,
+    samples: i64,
+}
+
+#[get("/runs?<filter>&<sort>&<resort>&<limit>&<page>")]
+async fn runs(conn: VaultDatabase, filter: Option<String>, sort: Option<String>, resort: Option<String>, limit: Option<usize>, page: Option<usize>) -> Template {
+    let mut warnings: Vec<String> = Vec::new();
+    let page = page.unwrap_or(1).max(1);
+    let limit = limit.or(Some(100));
+
+    let order = match resort.or(sort).filter(|s| !s.is_empty()).map(|s| Order::parse(&s)) {
+        Some(Ok(order)) if order.column.is_run_column() => order,
+        Some(Ok(order)) => {
+            warnings.push(format!("Runs can not be sorted by {}", order.column.name()));
+            LATEST_RUNS_FIRST
+        },
+        Some(Err(e)) => {
+            warnings.push(html_escape(&e));
+            LATEST_RUNS_FIRST
+        },
+        None => LATEST_RUNS_FIRST,
+    };
+    let parsed = match filter.as_deref().map(|f| parse_filters(f, &mut warnings)) {
+        Some(Ok(parsed)) => Ok(parsed),
+        Some(Err(())) => Err(()),
+        None => Ok(None),
+    };
+
+    let mut runs: Vec<RunSummary> = Vec::new();
+    let mut pagination = Pagination::default();
+    if let Ok(parsed) = parsed {
+        let offset = page_offset(limit, page);
+        let result = conn.run(move |c| {
+            crate::vaultdb::runs(c, parsed.as_ref(), order, limit, offset).map_err(|e| e.to_string())
+        }).await;
+        match result {
+            Ok(result) => {
+                runs = result.runs.into_iter().map(|(run, samples)| RunSummary { run, samples }).collect();
+                pagination = Pagination::new(result.total, runs.len(), limit, page);
+            },
+            Err(e) => warnings.push(html_escape(&e)),
+        }
+    }
+
+    Template::render("runs", context!{
+        filters: filter,
+        limit,
+        sort: order.to_string(),
+        warnings,
+        runs,
+        pagination,
     })
 }
 
+#[derive(Serialize)]
+struct RunSample {
+    sample: Sample,
+    fastqs: Vec<Fastq>,
+    /// Whether the sample is in the basket
+    selected: bool,
+}
+
+#[get("/run/<name>")]
+async fn run(conn: VaultDatabase, name: String, cookies: &CookieJar<'_>) -> Result<Template, Status> {
+    let selected_samples = browser_selection(&conn, cookies).await?;
+
+    let (run, samples) = conn.run(move |c| -> DbResult<_> {
+        let run = match crate::vaultdb::get_run(c, &name)? {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+        let samples = crate::vaultdb::run_samples(c, &name)?;
+        Ok(Some((run, samples)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+    let samples: Vec<RunSample> = samples
+        .into_iter()
+        .map(|(sample, fastqs)| {
+            let selected = selected_samples.contains(&sample.key);
+            RunSample { sample, fastqs, selected }
+        })
+        .collect();
+    let count = samples.len();
+
+    Ok(Template::render("run", context!{
+        run,
+        samples,
+        count,
+    }))
+}
+
+#[derive(Serialize)]
+struct SampleFastq {
+    fastq: Fastq,
+    /// Location of the file, within the zip file for zipped runs
+    path: String,
+}
+
+#[get("/sample/<id>")]
+async fn sample(conn: VaultDatabase, id: i32, cookies: &CookieJar<'_>) -> Result<Template, Status> {
+    let (sample, run, fastqs, related) = conn.run(move |c| -> DbResult<_> {
+        let sample = match crate::vaultdb::get_sample(c, id)? {
+            Some(sample) => sample,
+            None => return Ok(None),
+        };
+        let run = crate::vaultdb::get_run(c, &sample.run)?;
+        let fastqs = crate::vaultdb::sample_fastqs(c, id)?.unwrap_or_default();
+        let related = match &sample.dna_nr {
+            Some(dna_nr) => crate::vaultdb::samples_with_dna_nr(c, dna_nr, id)?,
+            None => Vec::new(),
+        };
+        Ok(Some((sample, run, fastqs, related)))
+    }).await.map_err(internal_error)?.ok_or(Status::NotFound)?;
+
+    // same distinction as in `SampleSheet::extract_fastqs`
+    let in_zip = matches!(&run, Some(r) if r.path.to_ascii_lowercase().ends_with(".zip"));
+    let fastqs: Vec<SampleFastq> = fastqs
+        .into_iter()
+        .map(|fastq| {
+            let path = match &run {
+                Some(r) if in_zip => format!("{} → {}", r.path, fastq.filename),
+                Some(r) => Path::new(&r.path).join(&fastq.filename).display().to_string(),
+                None => fastq.filename.clone(),
+            };
+            SampleFastq { fastq, path }
+        })
+        .collect();
+
+    let cells_source = match (&sample.cells, run.as_ref().and_then(|r| r.cellsheet.as_ref())) {
+        (None, _) => String::from("No cell count known"),
+        (Some(_), Some(cellsheet)) => format!("Converted from the DNA amount in cell sheet {}", cellsheet),
+        (Some(_), None) => String::from("Converted from the DNA amount in a cell sheet that is no longer known"),
+    };
+
+    let selected = browser_selection(&conn, cookies).await?.contains(&sample.key);
+
+    Ok(Template::render("sample", context!{
+        sample,
+        run,
+        fastqs,
+        in_zip,
+        cells_source,
+        related,
+        selected,
+    }))
+}
+
+#[derive(Serialize)]
+struct RunIssues {
+    run: Run,
+    issues: Vec<RunIssue>,
+}
+
+#[get("/issues")]
+async fn issues(conn: VaultDatabase) -> Result<Template, Status> {
+    let (runs, failed) = conn.run(|c| -> DbResult<_> {
+        let runs = crate::vaultdb::runs_with_issues(c)?;
+        let failed = crate::vaultdb::failed_runs(c)?;
+        Ok((runs, failed))
+    }).await.map_err(internal_error)?;
+    let runs: Vec<RunIssues> = runs
+        .into_iter()
+        .map(|(run, issues)| RunIssues { run, issues })
+        .collect();
+    let count = runs.len();
+
+    Ok(Template::render("issues", context!{
+        runs,
+        count,
+        failed,
+    }))
+}
+
+// share of `part` in `total` in percent, empty if unknown
+handlebars_helper!(percent: |part: Json, total: Json| match (part.as_f64(), total.as_f64()) {
+    (Some(part), Some(total)) if total > 0.0 => format!("{:.1}", part / total * 100.0),
+    _ => String::new(),
+});
+
+// value of a column header's sort button, toggling the direction if already sorted by it
+handlebars_helper!(sort_toggle: |sort: str, column: str| if sort == column {
+    format!("-{}", column)
+} else {
+    column.to_string()
+});
+
+// arrow indicating whether and how the results are sorted by a column
+handlebars_helper!(sort_arrow: |sort: str, column: str| if sort == column {
+    "▲"
+} else if sort.strip_prefix('-') == Some(column) {
+    "▼"
+} else {
+    ""
+});
+
+// file size in human readable units
+handlebars_helper!(filesize: |bytes: Json| match bytes.as_f64() {
+    Some(b) if b >= 1e9 => format!("{:.1} GB", b / 1e9),
+    Some(b) if b >= 1e6 => format!("{:.1} MB", b / 1e6),
+    Some(b) if b >= 1e3 => format!("{:.1} kB", b / 1e3),
+    Some(b) => format!("{} B", b),
+    None => String::new(),
+});
+
 pub fn customize_hbs(hbs: &mut Handlebars) {
-    hbs.register_escape_fn(no_escape);
+    hbs.register_helper("percent", Box::new(percent));
+    hbs.register_helper("sort_toggle", Box::new(sort_toggle));
+    hbs.register_helper("sort_arrow", Box::new(sort_arrow));
+    hbs.register_helper("filesize", Box::new(filesize));
     hbs.set_strict_mode(true);
 }
 
 #[rocket::main]
 pub async fn rocket ()  {
     let figment = rocket::Config::figment();
+
+    // extraction jobs are carried out next to the web server, in the same database
+    let extract_dirs: Vec<PathBuf> = figment.extract_inner("extract_dirs").unwrap_or_default();
+    match figment.extract_inner::<String>("databases.vault.url") {
+        Ok(connstr) => { std::thread::spawn(move || crate::jobs::worker(&connstr)); },
+        Err(e) => error!("Cannot start extraction worker: {}", e),
+    }
+
     if let Err(e) = rocket::custom(figment)
         .attach(VaultDatabase::fairing())
+        .manage(ExtractDirs(extract_dirs))
         .attach(Template::custom(|engines| { customize_hbs(&mut engines.handlebars)} ))
         .mount("/static", FileServer::from(relative!("static")))
-        .mount("/", routes![run_query, run_query_default, checkout])
+        .mount("/", routes![run_query, run_query_default, checkout, download_samplesheet, baskets, create_basket, basket, basket_zip, basket_add, basket_remove, basket_extract, jobs, job, runs, run, sample, issues])
+        .mount("/api/v1", crate::api::routes())
+        .register("/api/v1", crate::api::catchers())
         .launch()
         .await {
             error!("Could not launch rocket: {}", e);
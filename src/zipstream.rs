@@ -0,0 +1,272 @@
+//! A zip writer for sinks that cannot seek, such as HTTP responses.
+//!
+//! Files are stored without compression, which is all that makes sense for
+//! gzipped FASTQs anyway. Since sizes and CRCs are only known once a file has
+//! passed through, they follow each file in a data descriptor. Whether a file
+//! exceeds 4 GiB is not known when its header is written either, so every file
+//! is announced as ZIP64 and its data descriptor carries 64 bit sizes. The
+//! central directory only resorts to ZIP64 fields where needed.
+
+use std::io::{self, Read, Write};
+
+use chrono::{Datelike, Local, Timelike};
+use flate2::Crc;
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const END: u32 = 0x06054b50;
+
+/// Sizes and CRC follow in a data descriptor, names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+/// Version needed to extract: 4.5, for ZIP64
+const VERSION: u16 = 45;
+const ZIP64_EXTRA: u16 = 0x0001;
+/// Upper byte of "version made by": UNIX, so that the file mode is honored
+const MADE_BY_UNIX: u16 = 3 << 8;
+/// Regular file with mode 644
+const FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+
+/// Placeholder for 32 bit fields whose value is given in the ZIP64 extra field
+const MAX32: u64 = 0xffff_ffff;
+const MAX16: usize = 0xffff;
+
+/// What the central directory needs to know about a file
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+
+/// Writes a zip archive to `W` without seeking. Call `finish` to write the central
+/// directory, an archive that is merely dropped is incomplete.
+pub struct ZipStreamWriter<W: Write> {
+    out: W,
+    /// Bytes written so far
+    offset: u64,
+    entries: Vec<Entry>,
+    /// Modification time of all files in MS-DOS format
+    time: u16,
+    date: u16,
+    /// Sizes and offsets from which on ZIP64 fields are needed. Only lowered by tests.
+    zip64_from: u64,
+}
+
+impl<W: Write> ZipStreamWriter<W> {
+    /// Starts an archive. All files will carry the current time.
+    pub fn new(out: W) -> Self {
+        let now = Local::now();
+        ZipStreamWriter {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+            time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            date: (((now.year().max(1980) - 1980) << 9) as u32 | (now.month() << 5) | now.day()) as u16,
+            zip64_from: MAX32,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.write_all(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Adds a file named `name` with the contents of `content`. Returns the size of the file.
+    pub fn add<R: Read>(&mut self, name: &str, mut content: R) -> io::Result<u64> {
+        if name.len() > MAX16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("File name too long: {}", name)));
+        }
+        let offset = self.offset;
+
+        let mut header = Vec::with_capacity(50 + name.len());
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0); // stored
+        put_u16(&mut header, self.time);
+        put_u16(&mut header, self.date);
+        put_u32(&mut header, 0); // CRC, follows in the data descriptor like the sizes
+        put_u32(&mut header, MAX32 as u32);
+        put_u32(&mut header, MAX32 as u32);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 20); // extra field
+        header.extend_from_slice(name.as_bytes());
+        put_u16(&mut header, ZIP64_EXTRA);
+        put_u16(&mut header, 16);
+        put_u64(&mut header, 0); // size and compressed size, unknown yet
+        put_u64(&mut header, 0);
+        self.write(&header)?;
+
+        let mut crc = Crc::new();
+        let mut size: u64 = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = match content.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            crc.update(&buf[..n]);
+            self.write(&buf[..n])?;
+            size += n as u64;
+        }
+
+        // sizes are 64 bit since the local header has a ZIP64 extra field
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc.sum());
+        put_u64(&mut descriptor, size);
+        put_u64(&mut descriptor, size);
+        self.write(&descriptor)?;
+
+        self.entries.push(Entry { name: name.to_string(), crc: crc.sum(), size, offset });
+        Ok(size)
+    }
+
+    /// Writes the central directory and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.offset;
+        let mut directory = Vec::new();
+        for e in &self.entries {
+            let (large, far) = (e.size >= self.zip64_from, e.offset >= self.zip64_from);
+            let mut extra = Vec::new();
+            if large || far {
+                put_u16(&mut extra, ZIP64_EXTRA);
+                put_u16(&mut extra, 0); // size of the field, set below
+                if large {
+                    put_u64(&mut extra, e.size);
+                    put_u64(&mut extra, e.size);
+                }
+                if far {
+                    put_u64(&mut extra, e.offset);
+                }
+                let len = (extra.len() - 4) as u16;
+                extra[2..4].copy_from_slice(&len.to_le_bytes());
+            }
+            let size = if large { MAX32 } else { e.size };
+            let offset = if far { MAX32 } else { e.offset };
+
+            put_u32(&mut directory, CENTRAL_HEADER);
+            put_u16(&mut directory, MADE_BY_UNIX | VERSION);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, FLAGS);
+            put_u16(&mut directory, 0); // stored
+            put_u16(&mut directory, self.time);
+            put_u16(&mut directory, self.date);
+            put_u32(&mut directory, e.crc);
+            put_u32(&mut directory, size as u32);
+            put_u32(&mut directory, size as u32);
+            put_u16(&mut directory, e.name.len() as u16);
+            put_u16(&mut directory, extra.len() as u16);
+            put_u16(&mut directory, 0); // comment
+            put_u16(&mut directory, 0); // disk
+            put_u16(&mut directory, 0); // internal attributes
+            put_u32(&mut directory, FILE_ATTRIBUTES);
+            put_u32(&mut directory, offset as u32);
+            directory.extend_from_slice(e.name.as_bytes());
+            directory.extend_from_slice(&extra);
+        }
+        let size = directory.len() as u64;
+        let count = self.entries.len();
+
+        let zip64 = count >= MAX16 || size >= self.zip64_from || start >= self.zip64_from;
+        let mut end = Vec::new();
+        if zip64 {
+            let zip64_end = start + size;
+            put_u32(&mut end, ZIP64_END);
+            put_u64(&mut end, 44); // size of the remaining record
+            put_u16(&mut end, MADE_BY_UNIX | VERSION);
+            put_u16(&mut end, VERSION);
+            put_u32(&mut end, 0); // disk
+            put_u32(&mut end, 0); // disk with the central directory
+            put_u64(&mut end, count as u64);
+            put_u64(&mut end, count as u64);
+            put_u64(&mut end, size);
+            put_u64(&mut end, start);
+
+            put_u32(&mut end, ZIP64_LOCATOR);
+            put_u32(&mut end, 0); // disk with the ZIP64 end record
+            put_u64(&mut end, zip64_end);
+            put_u32(&mut end, 1); // number of disks
+        }
+        put_u32(&mut end, END);
+        put_u16(&mut end, 0); // disk
+        put_u16(&mut end, 0); // disk with the central directory
+        let (count, size, start) = if zip64 { (MAX16, MAX32, MAX32) } else { (count, size, start) };
+        put_u16(&mut end, count as u16);
+        put_u16(&mut end, count as u16);
+        put_u32(&mut end, size as u32);
+        put_u32(&mut end, start as u32);
+        put_u16(&mut end, 0); // comment
+
+        self.write(&directory)?;
+        self.write(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn readable() {
+        let mut zip = ZipStreamWriter::new(Vec::new());
+        assert_eq!(zip.add("a.fastq.gz", "ACGT".as_bytes()).unwrap(), 4);
+        assert_eq!(zip.add("empty.txt", io::empty()).unwrap(), 0);
+        let large = vec![7u8; 200_000];
+        zip.add("dir/large.bin", large.as_slice()).unwrap();
+        let archive = zip.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 3);
+        let mut content = Vec::new();
+        archive.by_name("a.fastq.gz").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"ACGT");
+        assert_eq!(archive.by_name("empty.txt").unwrap().size(), 0);
+        content.clear();
+        archive.by_name("dir/large.bin").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, large);
+    }
+
+    #[test]
+    fn zip64() {
+        // files and offsets beyond 100 bytes need ZIP64 fields, as would 4 GiB otherwise
+        let mut zip = ZipStreamWriter::new(Vec::new());
+        zip.zip64_from = 100;
+        zip.add("small.txt", "ACGT".as_bytes()).unwrap();
+        let large = vec![7u8; 1000];
+        zip.add("large.bin", large.as_slice()).unwrap();
+        zip.add("far.txt", "TGCA".as_bytes()).unwrap();
+        let archive = zip.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 3);
+        let mut content = Vec::new();
+        archive.by_name("large.bin").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, large);
+        content.clear();
+        archive.by_name("far.txt").unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"TGCA");
+    }
+}